@@ -0,0 +1,397 @@
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+// Rate at which adjtime(3) amortizes an offset, 500 microseconds per second.
+#[cfg(test)]
+const SLEW_RATE_PPM: f64 = 500.0;
+/// Largest frequency correction the Linux kernel accepts.
+pub const MAX_FREQUENCY_PPM: f64 = 500.0;
+// adjtimex(2) expresses frequencies in ppm with a 16-bit fraction.
+#[cfg(target_os = "linux")]
+const KERNEL_FREQUENCY_SCALE: f64 = 65536.0;
+
+/// The clock being disciplined.
+pub trait SystemClock {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Jumps the clock by `offset`.
+    fn step(&mut self, offset: chrono::Duration) -> Result<(), String>;
+
+    /// Runs the clock slightly fast or slow until it has gained `offset`,
+    /// replacing any correction still in progress.
+    fn slew(&mut self, offset: chrono::Duration) -> Result<(), String>;
+
+    /// Frequency correction currently applied, in parts per million.
+    fn frequency(&self) -> Result<f64, String>;
+
+    /// Makes the clock run `ppm` parts per million faster than its
+    /// oscillator, or slower when negative.
+    fn set_frequency(&mut self, ppm: f64) -> Result<(), String>;
+}
+
+/// What was done to the clock to correct an offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockAction {
+    Stepped(chrono::Duration),
+    Slewing(chrono::Duration),
+}
+
+impl fmt::Display for ClockAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClockAction::Stepped(offset) => {
+                write!(f, "stepped by {} ms", offset.num_milliseconds())
+            }
+            ClockAction::Slewing(offset) => {
+                write!(f, "slewing by {} ms", offset.num_milliseconds())
+            }
+        }
+    }
+}
+
+/// Slews offsets up to `step_threshold` and steps anything larger, like
+/// ntpd's 128 ms rule. Without a threshold the clock is always stepped.
+pub fn correct(
+    clock: &mut dyn SystemClock,
+    offset: chrono::Duration,
+    step_threshold: Option<chrono::Duration>,
+) -> Result<ClockAction, String> {
+    match step_threshold {
+        Some(threshold) if offset.abs() <= threshold => {
+            clock.slew(offset).map(|_| ClockAction::Slewing(offset))
+        }
+        _ => clock.step(offset).map(|_| ClockAction::Stepped(offset)),
+    }
+}
+
+/// The operating system's real-time clock.
+pub struct OsClock;
+
+#[cfg(not(target_os = "windows"))]
+impl SystemClock for OsClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn step(&mut self, offset: chrono::Duration) -> Result<(), String> {
+        // The offset is added to the clock right before writing it back, so
+        // the time spent since the sample was taken is kept, down to the
+        // nanosecond.
+        let target = to_timespec(Utc::now() + offset);
+        let result = unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &target) };
+        match result {
+            0 => Ok(()),
+            _ => Err("Error setting system time".into()),
+        }
+    }
+
+    fn slew(&mut self, offset: chrono::Duration) -> Result<(), String> {
+        use libc::{adjtime, suseconds_t, time_t, timeval};
+
+        let micros = offset
+            .num_microseconds()
+            .ok_or("Offset too large to slew the clock")?;
+        let delta = timeval {
+            tv_sec: micros.div_euclid(1_000_000) as time_t,
+            tv_usec: micros.rem_euclid(1_000_000) as suseconds_t,
+        };
+
+        let result = unsafe { adjtime(&delta, std::ptr::null_mut()) };
+        match result {
+            0 => Ok(()),
+            _ => Err("Error slewing system time".into()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn frequency(&self) -> Result<f64, String> {
+        let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+        match unsafe { libc::adjtimex(&mut timex) } {
+            -1 => Err("Error reading the clock frequency".into()),
+            _ => Ok(timex.freq as f64 / KERNEL_FREQUENCY_SCALE),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_frequency(&mut self, ppm: f64) -> Result<(), String> {
+        let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+        timex.modes = libc::ADJ_FREQUENCY;
+        timex.freq = (ppm.clamp(-MAX_FREQUENCY_PPM, MAX_FREQUENCY_PPM) * KERNEL_FREQUENCY_SCALE)
+            as libc::c_long;
+        match unsafe { libc::adjtimex(&mut timex) } {
+            -1 => Err("Error setting the clock frequency".into()),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn frequency(&self) -> Result<f64, String> {
+        Err("Frequency discipline is only supported on Linux".into())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_frequency(&mut self, _ppm: f64) -> Result<(), String> {
+        Err("Frequency discipline is only supported on Linux".into())
+    }
+}
+
+// Whole seconds, rounded down even before 1970, and the nanoseconds past
+// them. A leap second's extra nanoseconds are dropped, the kernel takes
+// 0..1e9.
+#[cfg(not(target_os = "windows"))]
+fn to_timespec(time: DateTime<Utc>) -> libc::timespec {
+    libc::timespec {
+        tv_sec: time.timestamp() as libc::time_t,
+        tv_nsec: time.timestamp_subsec_nanos().min(999_999_999) as libc::c_long,
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl SystemClock for OsClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn step(&mut self, offset: chrono::Duration) -> Result<(), String> {
+        use chrono::{Datelike, Timelike};
+        use winapi::{
+            shared::minwindef::{FALSE, WORD},
+            um::{minwinbase::SYSTEMTIME, sysinfoapi::SetSystemTime},
+        };
+
+        let datetime = Utc::now() + offset;
+        let system_time = SYSTEMTIME {
+            wYear: datetime.year() as WORD,
+            wMonth: datetime.month() as WORD,
+            wDay: datetime.day() as WORD,
+            wHour: datetime.hour() as WORD,
+            wMinute: datetime.minute() as WORD,
+            wSecond: datetime.second() as WORD,
+            wMilliseconds: datetime.timestamp_subsec_millis() as WORD,
+            wDayOfWeek: 0,
+        };
+
+        let result = unsafe { SetSystemTime(&system_time) };
+        match result {
+            FALSE => Err("Error setting system time".into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn slew(&mut self, _offset: chrono::Duration) -> Result<(), String> {
+        Err("Slewing is not supported on Windows, use the default step mode".into())
+    }
+
+    fn frequency(&self) -> Result<f64, String> {
+        Err("Frequency discipline is not supported on Windows".into())
+    }
+
+    fn set_frequency(&mut self, _ppm: f64) -> Result<(), String> {
+        Err("Frequency discipline is not supported on Windows".into())
+    }
+}
+
+/// A clock that only exists in memory, for tests. Time only passes when
+/// `advance` is called, so everything that happens to it is reproducible.
+///
+/// It starts `offset` away from the true time, runs `drift_ppm` parts per
+/// million fast (or slow when negative) and slews at the same rate as
+/// adjtime(3).
+#[cfg(test)]
+pub struct SimulatedClock {
+    // The time a perfect clock would show, only moved by `advance`.
+    true_time: DateTime<Utc>,
+    // Simulated time at the true time `origin`, before any slew.
+    base: DateTime<Utc>,
+    origin: DateTime<Utc>,
+    drift_ppm: f64,
+    frequency_ppm: f64,
+    slew: Option<(DateTime<Utc>, chrono::Duration)>,
+}
+
+#[cfg(test)]
+impl SimulatedClock {
+    pub fn new(true_time: DateTime<Utc>, offset: chrono::Duration, drift_ppm: f64) -> Self {
+        SimulatedClock {
+            true_time,
+            base: true_time + offset,
+            origin: true_time,
+            drift_ppm,
+            frequency_ppm: 0.0,
+            slew: None,
+        }
+    }
+
+    pub fn true_time(&self) -> DateTime<Utc> {
+        self.true_time
+    }
+
+    /// Lets `elapsed` of true time pass.
+    pub fn advance(&mut self, elapsed: chrono::Duration) {
+        self.true_time += elapsed;
+    }
+
+    // Part of the pending slew applied by now.
+    fn slewed(&self) -> chrono::Duration {
+        let Some((started, offset)) = self.slew else {
+            return chrono::Duration::zero();
+        };
+        let elapsed = seconds(self.true_time - started);
+        let applied = chrono::Duration::nanoseconds((elapsed * SLEW_RATE_PPM * 1e3) as i64);
+        match offset < chrono::Duration::zero() {
+            true => offset.max(-applied),
+            false => offset.min(applied),
+        }
+    }
+
+    // Simulated time without the slew.
+    fn drifted(&self) -> DateTime<Utc> {
+        let elapsed = seconds(self.true_time - self.origin);
+        let drifted = elapsed * (1.0 + (self.drift_ppm + self.frequency_ppm) / 1e6);
+        self.base + chrono::Duration::nanoseconds((drifted * 1e9) as i64)
+    }
+
+    // Folds the elapsed time and the part of the slew already applied into
+    // `base`.
+    fn rebase(&mut self) {
+        self.base = self.now();
+        self.origin = self.true_time;
+        self.slew = None;
+    }
+}
+
+/// `duration` in seconds, as a float for rate calculations.
+pub fn seconds(duration: chrono::Duration) -> f64 {
+    duration.num_nanoseconds().unwrap_or(i64::MAX) as f64 / 1e9
+}
+
+#[cfg(test)]
+impl SystemClock for SimulatedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.drifted() + self.slewed()
+    }
+
+    fn step(&mut self, offset: chrono::Duration) -> Result<(), String> {
+        self.rebase();
+        self.base += offset;
+        Ok(())
+    }
+
+    fn slew(&mut self, offset: chrono::Duration) -> Result<(), String> {
+        self.rebase();
+        self.slew = Some((self.true_time, offset));
+        Ok(())
+    }
+
+    fn frequency(&self) -> Result<f64, String> {
+        Ok(self.frequency_ppm)
+    }
+
+    fn set_frequency(&mut self, ppm: f64) -> Result<(), String> {
+        // Restart the drift from now, leaving any slew in progress alone.
+        self.base = self.drifted();
+        self.origin = self.true_time;
+        self.frequency_ppm = ppm.clamp(-MAX_FREQUENCY_PPM, MAX_FREQUENCY_PPM);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::ms;
+    use chrono::{TimeZone, Timelike};
+
+    fn start() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 10, 15, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn simulated_time_only_passes_when_advanced() {
+        let mut clock = SimulatedClock::new(start(), ms(1500), 0.0);
+        assert_eq!(clock.now(), start() + ms(1500));
+        assert_eq!(clock.now(), start() + ms(1500));
+        clock.advance(chrono::Duration::seconds(60));
+        assert_eq!(clock.true_time(), start() + chrono::Duration::seconds(60));
+        assert_eq!(clock.now(), clock.true_time() + ms(1500));
+    }
+
+    #[test]
+    fn simulated_clock_drifts_until_its_frequency_is_corrected() {
+        let mut clock = SimulatedClock::new(start(), chrono::Duration::zero(), 100.0);
+        clock.advance(chrono::Duration::seconds(1000));
+        assert_eq!(clock.now() - clock.true_time(), ms(100));
+
+        clock.set_frequency(-100.0).unwrap();
+        clock.advance(chrono::Duration::seconds(1000));
+        assert_eq!(clock.now() - clock.true_time(), ms(100));
+        assert_eq!(clock.frequency(), Ok(-100.0));
+    }
+
+    #[test]
+    fn simulated_clock_slews_at_the_adjtime_rate() {
+        let mut clock = SimulatedClock::new(start(), ms(-10), 0.0);
+        clock.slew(ms(10)).unwrap();
+        clock.advance(chrono::Duration::seconds(10));
+        assert_eq!(clock.now() - clock.true_time(), ms(-5));
+        clock.advance(chrono::Duration::seconds(60));
+        assert_eq!(clock.now(), clock.true_time());
+    }
+
+    #[test]
+    fn steps_beyond_the_threshold_and_slews_below_it() {
+        let mut clock = SimulatedClock::new(start(), chrono::Duration::zero(), 0.0);
+        assert_eq!(
+            correct(&mut clock, ms(100), Some(ms(128))),
+            Ok(ClockAction::Slewing(ms(100)))
+        );
+        assert_eq!(clock.now(), start());
+
+        assert_eq!(
+            correct(&mut clock, ms(-200), Some(ms(128))),
+            Ok(ClockAction::Stepped(ms(-200)))
+        );
+        assert_eq!(clock.now(), start() - ms(200));
+
+        assert_eq!(
+            correct(&mut clock, ms(100), None),
+            Ok(ClockAction::Stepped(ms(100)))
+        );
+        assert_eq!(clock.now(), start() - ms(100));
+    }
+
+    #[test]
+    fn keeps_the_fraction_of_a_second_when_stepping() {
+        let mut clock =
+            SimulatedClock::new(start(), chrono::Duration::nanoseconds(-123_456_789), 0.0);
+        let offset = clock.true_time() - clock.now();
+        correct(&mut clock, offset, None).unwrap();
+        assert_eq!(clock.now(), start());
+
+        let mut clock = SimulatedClock::new(start(), chrono::Duration::zero(), 0.0);
+        correct(
+            &mut clock,
+            chrono::Duration::nanoseconds(-1_300_000_001),
+            None,
+        )
+        .unwrap();
+        assert_eq!(clock.now().timestamp(), start().timestamp() - 2);
+        assert_eq!(clock.now().timestamp_subsec_nanos(), 699_999_999);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn converts_times_to_valid_timespecs() {
+        let time = start() + chrono::Duration::nanoseconds(-1_300_000_001);
+        let timespec = to_timespec(time);
+        assert_eq!(timespec.tv_sec, start().timestamp() - 2);
+        assert_eq!(timespec.tv_nsec, 699_999_999);
+
+        let epoch = DateTime::UNIX_EPOCH - chrono::Duration::milliseconds(250);
+        let timespec = to_timespec(epoch);
+        assert_eq!((timespec.tv_sec, timespec.tv_nsec), (-1, 750_000_000));
+
+        let leap_second = start().with_nanosecond(1_500_000_000).unwrap();
+        assert_eq!(to_timespec(leap_second).tv_nsec, 999_999_999);
+    }
+}
@@ -0,0 +1,757 @@
+use crate::{
+    clock::SystemClock,
+    net,
+    pool::ServerPool,
+    source::{Sample, SourceError, TimeSource},
+};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use clap::ValueEnum;
+use std::{
+    fmt,
+    io::Read,
+    net::{SocketAddr, ToSocketAddrs},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// Label NIST puts in the eighth field of every daytime reply.
+const NIST_LABEL: &str = "UTC(NIST)";
+// Number of days between 0001-01-01 (CE day 1) and the MJD epoch, 1858-11-17.
+const MJD_EPOCH_FROM_CE: i32 = 678_576;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Mjd,
+    Date,
+    Time,
+    DaylightSaving,
+    LeapSecond,
+    Health,
+    Advance,
+    Label,
+    OnTimeMarker,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Field::Mjd => "MJD",
+            Field::Date => "date",
+            Field::Time => "time",
+            Field::DaylightSaving => "daylight saving code",
+            Field::LeapSecond => "leap second indicator",
+            Field::Health => "health",
+            Field::Advance => "msADV",
+            Field::Label => "UTC(NIST) label",
+            Field::OnTimeMarker => "on-time marker",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    MissingField(Field),
+    InvalidField { field: Field, value: String },
+    DateMismatch { mjd: u32, date: String },
+    TrailingData(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "empty daytime response"),
+            ParseError::MissingField(field) => {
+                write!(f, "daytime response is missing the {} field", field)
+            }
+            ParseError::InvalidField { field, value } => {
+                write!(
+                    f,
+                    "invalid {} field in daytime response: {:?}",
+                    field, value
+                )
+            }
+            ParseError::DateMismatch { mjd, date } => {
+                write!(
+                    f,
+                    "MJD {} does not match date {} in daytime response",
+                    mjd, date
+                )
+            }
+            ParseError::TrailingData(data) => {
+                write!(
+                    f,
+                    "unexpected trailing data in daytime response: {:?}",
+                    data
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A decoded NIST daytime record:
+/// `JJJJJ YR-MO-DA HH:MM:SS TT L H msADV UTC(NIST) OTM`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NistDaytime {
+    pub mjd: u32,
+    /// Calendar date derived from the MJD, checked against the YR-MO-DA field.
+    pub date: NaiveDate,
+    pub time: NaiveTime,
+    /// 00 for standard time, 50 for daylight saving time, otherwise the
+    /// number of days until the next transition.
+    pub daylight_saving: u8,
+    /// 0 for no leap second, 1 if one is added and 2 if one is removed at
+    /// the end of the month.
+    pub leap_second: u8,
+    /// 0 when the server is healthy, 1, 2 or 4 when it is degraded.
+    pub health: u8,
+    /// How far the server advanced the timestamp (msADV) so that it matches
+    /// the moment the on-time marker reaches the client.
+    pub advance: chrono::Duration,
+    pub on_time_marker: char,
+}
+
+impl NistDaytime {
+    pub fn parse(response: &str) -> Result<Self, ParseError> {
+        let mut fields = response.split_whitespace();
+        let mut next = |field: Field| fields.next().ok_or(ParseError::MissingField(field));
+
+        let (mjd, date) = match next(Field::Mjd) {
+            Ok(value) => {
+                let mjd = parse_number::<u32>(value, Field::Mjd)?;
+                (
+                    mjd,
+                    mjd_to_date(mjd).ok_or_else(|| invalid(Field::Mjd, value))?,
+                )
+            }
+            Err(_) => return Err(ParseError::Empty),
+        };
+
+        let date_field = next(Field::Date)?;
+        let (year, month, day) = parse_triplet(date_field, '-', Field::Date)?;
+        if date.year().rem_euclid(100) as u32 != year || date.month() != month || date.day() != day
+        {
+            return Err(ParseError::DateMismatch {
+                mjd,
+                date: date_field.to_string(),
+            });
+        }
+
+        let time_field = next(Field::Time)?;
+        let (hour, minute, second) = parse_triplet(time_field, ':', Field::Time)?;
+        let time = NaiveTime::from_hms_opt(hour, minute, second)
+            .ok_or_else(|| invalid(Field::Time, time_field))?;
+
+        let daylight_saving =
+            parse_number::<u8>(next(Field::DaylightSaving)?, Field::DaylightSaving)?;
+        if daylight_saving > 99 {
+            return Err(invalid(Field::DaylightSaving, &daylight_saving.to_string()));
+        }
+
+        let leap_field = next(Field::LeapSecond)?;
+        let leap_second = parse_number::<u8>(leap_field, Field::LeapSecond)?;
+        if leap_second > 2 {
+            return Err(invalid(Field::LeapSecond, leap_field));
+        }
+
+        let health_field = next(Field::Health)?;
+        let health = parse_number::<u8>(health_field, Field::Health)?;
+        if !matches!(health, 0 | 1 | 2 | 4) {
+            return Err(invalid(Field::Health, health_field));
+        }
+
+        let advance_field = next(Field::Advance)?;
+        let advance_ms = parse_number::<f64>(advance_field, Field::Advance)?;
+        if !advance_ms.is_finite() || !(0.0..=1000.0).contains(&advance_ms) {
+            return Err(invalid(Field::Advance, advance_field));
+        }
+        let advance = chrono::Duration::nanoseconds((advance_ms * 1_000_000.0).round() as i64);
+
+        let label = next(Field::Label)?;
+        if label != NIST_LABEL {
+            return Err(invalid(Field::Label, label));
+        }
+
+        let marker_field = next(Field::OnTimeMarker)?;
+        let on_time_marker = match marker_field {
+            "*" => '*',
+            "#" => '#',
+            _ => return Err(invalid(Field::OnTimeMarker, marker_field)),
+        };
+
+        let rest: Vec<&str> = fields.collect();
+        if !rest.is_empty() {
+            return Err(ParseError::TrailingData(rest.join(" ")));
+        }
+
+        Ok(NistDaytime {
+            mjd,
+            date,
+            time,
+            daylight_saving,
+            leap_second,
+            health,
+            advance,
+            on_time_marker,
+        })
+    }
+
+    /// The time printed in the reply, which NIST already advanced by msADV:
+    /// its estimate of the moment the on-time marker arrives.
+    pub fn on_time_marker_time(&self) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&NaiveDateTime::new(self.date, self.time))
+    }
+
+    /// The server's clock when it sent the reply, with msADV taken back out.
+    pub fn server_time(&self) -> DateTime<Utc> {
+        self.on_time_marker_time() - self.advance
+    }
+
+    /// Estimated UTC at the moment the reply was received, given the round
+    /// trip we measured for the connection.
+    pub fn receive_time(
+        &self,
+        compensation: Compensation,
+        round_trip: std::time::Duration,
+    ) -> DateTime<Utc> {
+        match compensation {
+            Compensation::Nist => self.on_time_marker_time(),
+            Compensation::Measured => {
+                let one_way =
+                    chrono::Duration::from_std(round_trip / 2).unwrap_or(chrono::Duration::zero());
+                self.server_time() + one_way
+            }
+        }
+    }
+}
+
+/// Which network delay estimate to apply to a daytime reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compensation {
+    /// Trust the msADV advance NIST applied to the timestamp.
+    Nist,
+    /// Remove msADV and add half of our own measured round trip instead.
+    Measured,
+}
+
+impl FromStr for NistDaytime {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NistDaytime::parse(s)
+    }
+}
+
+/// Converts a Modified Julian Date into a calendar date.
+pub fn mjd_to_date(mjd: u32) -> Option<NaiveDate> {
+    i32::try_from(mjd)
+        .ok()
+        .and_then(|mjd| mjd.checked_add(MJD_EPOCH_FROM_CE))
+        .and_then(NaiveDate::from_num_days_from_ce_opt)
+}
+
+fn invalid(field: Field, value: &str) -> ParseError {
+    ParseError::InvalidField {
+        field,
+        value: value.to_string(),
+    }
+}
+
+fn parse_number<T: FromStr>(value: &str, field: Field) -> Result<T, ParseError> {
+    value.parse::<T>().map_err(|_| invalid(field, value))
+}
+
+// Splits `AA?BB?CC` on `separator` into three two-digit numbers.
+fn parse_triplet(
+    value: &str,
+    separator: char,
+    field: Field,
+) -> Result<(u32, u32, u32), ParseError> {
+    let parts: Vec<&str> = value.split(separator).collect();
+    match parts.as_slice() {
+        [a, b, c]
+            if [a, b, c]
+                .iter()
+                .all(|part| part.len() == 2 && part.bytes().all(|b| b.is_ascii_digit())) =>
+        {
+            Ok((
+                parse_number(a, field)?,
+                parse_number(b, field)?,
+                parse_number(c, field)?,
+            ))
+        }
+        _ => Err(invalid(field, value)),
+    }
+}
+
+/// What to do with a reply whose health digit is not 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthPolicy {
+    Reject,
+    Warn,
+    AcceptUpTo(u8),
+}
+
+impl HealthPolicy {
+    pub fn check(&self, daytime: &NistDaytime) -> Result<(), String> {
+        match (self, daytime.health) {
+            (_, 0) => Ok(()),
+            (HealthPolicy::Warn, health) => {
+                println!("Warning: NIST server reports health {}", health);
+                Ok(())
+            }
+            (HealthPolicy::AcceptUpTo(max), health) if health <= *max => Ok(()),
+            (_, health) => Err(format!("NIST server reports unhealthy status {}", health)),
+        }
+    }
+}
+
+impl FromStr for HealthPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(HealthPolicy::Reject),
+            "warn" => Ok(HealthPolicy::Warn),
+            _ => s.parse::<u8>().map(HealthPolicy::AcceptUpTo).map_err(|_| {
+                format!(
+                    "invalid health policy {:?}, expected reject, warn or a maximum health level",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+struct DaytimeReply {
+    response: String,
+    // When our final handshake ACK left, which is what makes the server
+    // stamp and send its reply.
+    connected: Instant,
+    received: Instant,
+}
+
+impl DaytimeReply {
+    fn round_trip(&self) -> Duration {
+        self.received - self.connected
+    }
+}
+
+// The server writes the time as soon as it accepts the connection, so the
+// reply is bracketed by the end of our connect and the end of our read.
+fn get_nist_server_time(
+    addr: SocketAddr,
+    timeouts: net::Timeouts,
+) -> Result<DaytimeReply, std::io::Error> {
+    let mut stream = net::tcp_connect(addr, timeouts)?;
+    let connected = Instant::now();
+    let mut buffer = [0u8; 256];
+    let bytes_read = stream.read(&mut buffer)?;
+    let received = Instant::now();
+
+    let time_string = String::from_utf8_lossy(&buffer[..bytes_read])
+        .trim()
+        .to_string();
+
+    Ok(DaytimeReply {
+        response: time_string,
+        connected,
+        received,
+    })
+}
+
+enum QueryError {
+    // The server turned the connection away or closed it without an answer,
+    // which is how NIST treats clients it considers abusive.
+    Refused(String),
+    Failed(SourceError),
+}
+
+fn is_refusal(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset
+    )
+}
+
+/// NIST daytime protocol client that fails over between the servers of a
+/// pool.
+pub struct DaytimeSource {
+    pool: ServerPool,
+    policy: HealthPolicy,
+    compensation: Compensation,
+    timeouts: net::Timeouts,
+}
+
+impl DaytimeSource {
+    pub fn new(
+        pool: ServerPool,
+        policy: HealthPolicy,
+        compensation: Compensation,
+        timeouts: net::Timeouts,
+    ) -> Self {
+        DaytimeSource {
+            pool,
+            policy,
+            compensation,
+            timeouts,
+        }
+    }
+
+    // Queries every address the server name resolves to until one of them
+    // returns a reply that passes the health policy.
+    fn sample_server(&self, server: &str, clock: &dyn SystemClock) -> Result<Sample, QueryError> {
+        let addrs = server
+            .to_socket_addrs()
+            .map_err(|e| QueryError::Failed(format!("Error resolving {}: {}", server, e).into()))?;
+
+        let mut last_error =
+            QueryError::Failed(format!("No addresses found for {}", server).into());
+        for addr in addrs {
+            let reply = get_nist_server_time(addr, self.timeouts);
+            let local_time = clock.now();
+            let reply = match reply {
+                Ok(reply) if reply.response.is_empty() => {
+                    last_error = QueryError::Refused("Empty reply".into());
+                    continue;
+                }
+                Ok(reply) => Ok(reply),
+                Err(e) if is_refusal(&e) => {
+                    last_error = QueryError::Refused(e.to_string());
+                    continue;
+                }
+                Err(e) => Err(net::io_error(e)),
+            };
+            let sample = reply.and_then(|reply| {
+                let daytime = NistDaytime::parse(&reply.response).map_err(|e| e.to_string())?;
+                self.policy.check(&daytime)?;
+                Ok(Sample {
+                    source: format!("daytime {} ({})", server, addr),
+                    server_time: daytime.receive_time(self.compensation, reply.round_trip()),
+                    local_time,
+                    sent: reply.connected,
+                    received: reply.received,
+                    // The reply only has whole seconds.
+                    uncertainty: chrono::Duration::seconds(1)
+                        + chrono::Duration::from_std(reply.round_trip() / 2)
+                            .unwrap_or(chrono::Duration::zero()),
+                })
+            });
+            match sample {
+                Ok(sample) => return Ok(sample),
+                Err(e) => last_error = QueryError::Failed(e),
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+impl TimeSource for DaytimeSource {
+    fn sample(&mut self, clock: &dyn SystemClock) -> Result<Sample, SourceError> {
+        let mut last_error =
+            SourceError::from("Every daytime server is backing off after refusing us");
+        for server in self.pool.candidates() {
+            self.pool.begin_query(&server);
+            match self.sample_server(&server, clock) {
+                Ok(sample) => {
+                    self.pool.record_success(&server);
+                    return Ok(sample);
+                }
+                Err(QueryError::Refused(e)) => {
+                    self.pool.record_refusal(&server);
+                    last_error = format!("{} refused the query: {}", server, e).into();
+                }
+                Err(QueryError::Failed(e)) => {
+                    println!("Skipping NIST server {}: {}", server, e);
+                    self.pool.record_failure(&server);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORD: &str = "60598 24-10-15 12:34:56 00 0 0 50.0 UTC(NIST) *";
+    const FIELDS: [Field; 9] = [
+        Field::Mjd,
+        Field::Date,
+        Field::Time,
+        Field::DaylightSaving,
+        Field::LeapSecond,
+        Field::Health,
+        Field::Advance,
+        Field::Label,
+        Field::OnTimeMarker,
+    ];
+
+    // RECORD with the field at `index` replaced.
+    fn with_field(index: usize, value: &str) -> String {
+        let mut fields: Vec<&str> = RECORD.split(' ').collect();
+        fields[index] = value;
+        fields.join(" ")
+    }
+
+    #[test]
+    fn parses_a_valid_record() {
+        let daytime = NistDaytime::parse(RECORD).unwrap();
+        assert_eq!(daytime.mjd, 60598);
+        assert_eq!(daytime.date, NaiveDate::from_ymd_opt(2024, 10, 15).unwrap());
+        assert_eq!(daytime.time, NaiveTime::from_hms_opt(12, 34, 56).unwrap());
+        assert_eq!(daytime.daylight_saving, 0);
+        assert_eq!(daytime.leap_second, 0);
+        assert_eq!(daytime.health, 0);
+        assert_eq!(daytime.advance, chrono::Duration::milliseconds(50));
+        assert_eq!(daytime.on_time_marker, '*');
+        assert_eq!(
+            daytime.server_time(),
+            Utc.with_ymd_and_hms(2024, 10, 15, 12, 34, 56).unwrap()
+                - chrono::Duration::milliseconds(50)
+        );
+    }
+
+    #[test]
+    fn accepts_surrounding_whitespace() {
+        let reply = format!("\n{}\r\n", RECORD);
+        assert!(NistDaytime::parse(&reply).is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_replies() {
+        assert_eq!(NistDaytime::parse(""), Err(ParseError::Empty));
+        assert_eq!(NistDaytime::parse(" \r\n"), Err(ParseError::Empty));
+    }
+
+    #[test]
+    fn reports_each_missing_field() {
+        let fields: Vec<&str> = RECORD.split(' ').collect();
+        for (count, &field) in FIELDS.iter().enumerate().skip(1) {
+            let truncated = fields[..count].join(" ");
+            assert_eq!(
+                NistDaytime::parse(&truncated),
+                Err(ParseError::MissingField(field)),
+                "{:?}",
+                truncated
+            );
+        }
+    }
+
+    #[test]
+    fn reports_each_invalid_field() {
+        let cases = [
+            (0, "6059x"),
+            (1, "24-1-15"),
+            (2, "12:34:60"),
+            (3, "100"),
+            (4, "3"),
+            (5, "3"),
+            (6, "1000.1"),
+            (7, "UTC(USNO)"),
+            (8, "!"),
+        ];
+        for (index, value) in cases {
+            assert_eq!(
+                NistDaytime::parse(&with_field(index, value)),
+                Err(ParseError::InvalidField {
+                    field: FIELDS[index],
+                    value: value.to_string(),
+                }),
+                "{:?}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let reply = format!("{} extra words", RECORD);
+        assert_eq!(
+            NistDaytime::parse(&reply),
+            Err(ParseError::TrailingData("extra words".into()))
+        );
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn converts_mjd_across_century_boundaries() {
+        assert_eq!(mjd_to_date(0), Some(date(1858, 11, 17)));
+        assert_eq!(mjd_to_date(51543), Some(date(1999, 12, 31)));
+        assert_eq!(mjd_to_date(51544), Some(date(2000, 1, 1)));
+        assert_eq!(mjd_to_date(88069), Some(date(2100, 1, 1)));
+    }
+
+    #[test]
+    fn converts_mjd_around_leap_days() {
+        // 2024 is a leap year, 2100 is not.
+        assert_eq!(mjd_to_date(60369), Some(date(2024, 2, 29)));
+        assert_eq!(mjd_to_date(60370), Some(date(2024, 3, 1)));
+        assert_eq!(mjd_to_date(88127), Some(date(2100, 2, 28)));
+        assert_eq!(mjd_to_date(88128), Some(date(2100, 3, 1)));
+    }
+
+    #[test]
+    fn takes_the_century_from_the_mjd() {
+        let record = "88128 00-03-01 00:00:00 00 0 0 50.0 UTC(NIST) *";
+        assert_eq!(NistDaytime::parse(record).unwrap().date, date(2100, 3, 1));
+        let record = "51544 00-01-01 00:00:00 00 0 0 50.0 UTC(NIST) *";
+        assert_eq!(NistDaytime::parse(record).unwrap().date, date(2000, 1, 1));
+    }
+
+    #[test]
+    fn rejects_dates_that_disagree_with_the_mjd() {
+        assert_eq!(
+            NistDaytime::parse(&with_field(1, "24-10-16")),
+            Err(ParseError::DateMismatch {
+                mjd: 60598,
+                date: "24-10-16".into(),
+            })
+        );
+        // 2100-02-29 doesn't exist, the day after 02-28 is 03-01.
+        let record = "88128 00-02-29 00:00:00 00 0 0 50.0 UTC(NIST) *";
+        assert_eq!(
+            NistDaytime::parse(record),
+            Err(ParseError::DateMismatch {
+                mjd: 88128,
+                date: "00-02-29".into(),
+            })
+        );
+    }
+
+    // Replies that made the original parser index out of bounds or unwrap
+    // an error.
+    #[test]
+    fn rejects_truncated_replies_without_panicking() {
+        assert_eq!(
+            NistDaytime::parse("60598 24-10"),
+            Err(ParseError::InvalidField {
+                field: Field::Date,
+                value: "24-10".into(),
+            })
+        );
+        assert_eq!(
+            NistDaytime::parse("60598 24-10-15 12:3"),
+            Err(ParseError::InvalidField {
+                field: Field::Time,
+                value: "12:3".into(),
+            })
+        );
+        assert_eq!(
+            NistDaytime::parse("60598 24-10-15 12:34:56 00 0 0"),
+            Err(ParseError::MissingField(Field::Advance))
+        );
+    }
+
+    #[test]
+    fn parses_health_policies() {
+        assert_eq!("reject".parse(), Ok(HealthPolicy::Reject));
+        assert_eq!("warn".parse(), Ok(HealthPolicy::Warn));
+        assert_eq!("2".parse(), Ok(HealthPolicy::AcceptUpTo(2)));
+        assert!("ignore".parse::<HealthPolicy>().is_err());
+        assert!("-1".parse::<HealthPolicy>().is_err());
+        assert!("".parse::<HealthPolicy>().is_err());
+    }
+
+    #[test]
+    fn checks_the_health_digit() {
+        let healthy = NistDaytime::parse(RECORD).unwrap();
+        let unhealthy = NistDaytime::parse(&with_field(5, "2")).unwrap();
+        for policy in [
+            HealthPolicy::Reject,
+            HealthPolicy::Warn,
+            HealthPolicy::AcceptUpTo(0),
+        ] {
+            assert_eq!(policy.check(&healthy), Ok(()));
+        }
+        assert!(HealthPolicy::Reject.check(&unhealthy).is_err());
+        assert_eq!(HealthPolicy::Warn.check(&unhealthy), Ok(()));
+        assert!(HealthPolicy::AcceptUpTo(1).check(&unhealthy).is_err());
+        assert_eq!(HealthPolicy::AcceptUpTo(2).check(&unhealthy), Ok(()));
+    }
+
+    // A daytime server that answers one connection with `reply`.
+    fn stand_in_server(reply: String) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            use std::io::Write;
+            let (mut socket, _) = listener.accept().unwrap();
+            socket.write_all(reply.as_bytes()).unwrap();
+        });
+        address
+    }
+
+    #[test]
+    fn fails_over_from_an_unhealthy_server() {
+        let unhealthy = stand_in_server(format!("\n{}\n", with_field(5, "2")));
+        let healthy = stand_in_server(format!("\n{}\n", RECORD));
+        let pool = ServerPool::new(
+            &[unhealthy.clone(), healthy.clone()],
+            crate::pool::ServerOrder::InOrder,
+        );
+        let timeouts = net::Timeouts {
+            connect: Duration::from_secs(2),
+            read: Duration::from_secs(2),
+        };
+        let mut source =
+            DaytimeSource::new(pool, HealthPolicy::Reject, Compensation::Nist, timeouts);
+        let sample = source.sample(&crate::clock::OsClock).unwrap();
+        assert!(sample.source.contains(&healthy), "{}", sample.source);
+        assert_eq!(
+            sample.server_time,
+            Utc.with_ymd_and_hms(2024, 10, 15, 12, 34, 56).unwrap()
+        );
+    }
+
+    #[test]
+    fn trusts_the_nist_advance() {
+        let daytime = NistDaytime::parse(RECORD).unwrap();
+        let printed = Utc.with_ymd_and_hms(2024, 10, 15, 12, 34, 56).unwrap();
+        for round_trip in [Duration::ZERO, Duration::from_millis(300)] {
+            assert_eq!(
+                daytime.receive_time(Compensation::Nist, round_trip),
+                printed
+            );
+        }
+    }
+
+    #[test]
+    fn replaces_the_advance_with_half_the_round_trip() {
+        let daytime = NistDaytime::parse(RECORD).unwrap();
+        let printed = Utc.with_ymd_and_hms(2024, 10, 15, 12, 34, 56).unwrap();
+        assert_eq!(
+            daytime.receive_time(Compensation::Measured, Duration::from_millis(30)),
+            printed - chrono::Duration::milliseconds(50) + chrono::Duration::milliseconds(15)
+        );
+        assert_eq!(
+            daytime.receive_time(Compensation::Measured, Duration::from_millis(100)),
+            printed
+        );
+        assert_eq!(
+            daytime.receive_time(Compensation::Measured, Duration::ZERO),
+            printed - chrono::Duration::milliseconds(50)
+        );
+    }
+
+    #[test]
+    fn takes_the_advance_with_its_fraction() {
+        let daytime = NistDaytime::parse(&with_field(6, "12.5")).unwrap();
+        let printed = Utc.with_ymd_and_hms(2024, 10, 15, 12, 34, 56).unwrap();
+        assert_eq!(
+            daytime.receive_time(Compensation::Measured, Duration::from_millis(5)),
+            printed - chrono::Duration::microseconds(12_500)
+                + chrono::Duration::microseconds(2_500)
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    env,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+};
+
+const DEFAULT_SYSTEM_BUS: &str = "/var/run/dbus/system_bus_socket";
+
+// Largest message the specification allows, 128 MiB.
+const MAX_MESSAGE_LEN: usize = 1 << 27;
+
+const METHOD_CALL: u8 = 1;
+const SIGNAL: u8 = 4;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// A signal broadcast on the bus.
+pub struct Signal {
+    pub interface: String,
+    pub member: String,
+    big_endian: bool,
+    body: Vec<u8>,
+}
+
+impl Signal {
+    /// The first argument, for signals whose first argument is a boolean.
+    pub fn bool_arg(&self) -> Option<bool> {
+        let bytes: [u8; 4] = self.body.get(..4)?.try_into().ok()?;
+        Some(read_u32(bytes, self.big_endian) != 0)
+    }
+}
+
+/// Just enough of a D-Bus client to subscribe to signals on the system bus.
+pub struct SystemBus {
+    stream: UnixStream,
+    serial: u32,
+}
+
+impl SystemBus {
+    pub fn connect() -> io::Result<Self> {
+        let path = env::var("DBUS_SYSTEM_BUS_ADDRESS")
+            .ok()
+            .and_then(|address| {
+                address
+                    .split(';')
+                    .find_map(|address| address.strip_prefix("unix:path="))
+                    .map(str::to_string)
+            })
+            .unwrap_or_else(|| DEFAULT_SYSTEM_BUS.to_string());
+        let mut stream = UnixStream::connect(path)?;
+
+        // The bus knows who we are from the socket, EXTERNAL only asks it to
+        // check that we are who we claim to be.
+        let uid: String = unsafe { libc::getuid() }
+            .to_string()
+            .bytes()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        stream.write_all(format!("\0AUTH EXTERNAL {}\r\n", uid).as_bytes())?;
+        let reply = read_line(&mut stream)?;
+        if !reply.starts_with("OK ") {
+            return Err(io::Error::other(format!(
+                "D-Bus authentication failed: {}",
+                reply.trim()
+            )));
+        }
+        stream.write_all(b"BEGIN\r\n")?;
+
+        let mut bus = SystemBus { stream, serial: 0 };
+        bus.call_bus("Hello", None)?;
+        Ok(bus)
+    }
+
+    /// Asks the bus to forward the messages matching `rule`, e.g.
+    /// `type='signal',interface='...'`.
+    pub fn add_match(&mut self, rule: &str) -> io::Result<()> {
+        self.call_bus("AddMatch", Some(rule))
+    }
+
+    /// Blocks until the next signal arrives, skipping replies to our calls.
+    pub fn next_signal(&mut self) -> io::Result<Signal> {
+        loop {
+            let mut fixed = [0u8; 16];
+            self.stream.read_exact(&mut fixed)?;
+            let big_endian = match fixed[0] {
+                b'l' => false,
+                b'B' => true,
+                _ => return Err(io::Error::other("Invalid D-Bus message")),
+            };
+            let at = |i: usize| read_u32(fixed[i..i + 4].try_into().unwrap(), big_endian) as usize;
+            let (body_length, fields_length) = (at(4), at(12));
+            let length = align(16 + fields_length, 8) + body_length;
+            if length > MAX_MESSAGE_LEN {
+                return Err(io::Error::other("D-Bus message too long"));
+            }
+
+            let mut message = fixed.to_vec();
+            message.resize(length, 0);
+            self.stream.read_exact(&mut message[16..])?;
+            if fixed[1] == SIGNAL {
+                return parse_signal(&message, fields_length, big_endian);
+            }
+        }
+    }
+
+    // Calls a method of the bus itself with at most one string argument.
+    // Replies are skipped by `next_signal`.
+    fn call_bus(&mut self, member: &str, arg: Option<&str>) -> io::Result<()> {
+        self.serial += 1;
+        let mut message = vec![b'l', METHOD_CALL, 0, 1];
+        put_u32(&mut message, 0); // body length, filled in below
+        put_u32(&mut message, self.serial);
+        put_u32(&mut message, 0); // header fields length, filled in below
+
+        let fields_start = message.len();
+        put_field(&mut message, FIELD_PATH, b'o', "/org/freedesktop/DBus");
+        put_field(&mut message, FIELD_INTERFACE, b's', "org.freedesktop.DBus");
+        put_field(&mut message, FIELD_MEMBER, b's', member);
+        put_field(
+            &mut message,
+            FIELD_DESTINATION,
+            b's',
+            "org.freedesktop.DBus",
+        );
+        if arg.is_some() {
+            put_field(&mut message, FIELD_SIGNATURE, b'g', "s");
+        }
+        let fields_length = (message.len() - fields_start) as u32;
+        message[12..16].copy_from_slice(&fields_length.to_le_bytes());
+        message.resize(align(message.len(), 8), 0);
+
+        let body_start = message.len();
+        if let Some(arg) = arg {
+            put_string(&mut message, arg);
+        }
+        let body_length = (message.len() - body_start) as u32;
+        message[4..8].copy_from_slice(&body_length.to_le_bytes());
+
+        self.stream.write_all(&message)
+    }
+}
+
+// Picks the interface and member out of the header fields of a whole message.
+// The lengths inside come off the wire, so every read is checked.
+fn parse_signal(message: &[u8], fields_length: usize, big_endian: bool) -> io::Result<Signal> {
+    let invalid = || io::Error::other("Invalid D-Bus message");
+    let fields_end = 16 + fields_length;
+    let mut signal = Signal {
+        interface: String::new(),
+        member: String::new(),
+        big_endian,
+        body: message
+            .get(align(fields_end, 8)..)
+            .ok_or_else(invalid)?
+            .to_vec(),
+    };
+    let mut pos = 16;
+    while pos < fields_end {
+        pos = align(pos, 8);
+        let header = message.get(pos..pos + 4).ok_or_else(invalid)?;
+        let (code, signature) = (header[0], header[2]);
+        pos += 4;
+        match signature {
+            b's' | b'o' => {
+                pos = align(pos, 4);
+                let length = message.get(pos..pos + 4).ok_or_else(invalid)?;
+                let length = read_u32(length.try_into().unwrap(), big_endian) as usize;
+                let value = message.get(pos + 4..pos + 4 + length).ok_or_else(invalid)?;
+                let value = String::from_utf8_lossy(value).into_owned();
+                match code {
+                    FIELD_INTERFACE => signal.interface = value,
+                    FIELD_MEMBER => signal.member = value,
+                    _ => (),
+                }
+                pos += 4 + length + 1;
+            }
+            b'g' => pos += *message.get(pos).ok_or_else(invalid)? as usize + 2,
+            b'u' => pos = align(pos, 4) + 4,
+            _ => return Err(io::Error::other("Unexpected D-Bus header field")),
+        }
+    }
+    Ok(signal)
+}
+
+fn align(pos: usize, alignment: usize) -> usize {
+    pos.next_multiple_of(alignment)
+}
+
+fn read_u32(bytes: [u8; 4], big_endian: bool) -> u32 {
+    match big_endian {
+        true => u32::from_be_bytes(bytes),
+        false => u32::from_le_bytes(bytes),
+    }
+}
+
+fn put_u32(message: &mut Vec<u8>, value: u32) {
+    message.resize(align(message.len(), 4), 0);
+    message.extend_from_slice(&value.to_le_bytes());
+}
+
+// Strings and object paths: length, bytes and a terminating nul.
+fn put_string(message: &mut Vec<u8>, value: &str) {
+    put_u32(message, value.len() as u32);
+    message.extend_from_slice(value.as_bytes());
+    message.push(0);
+}
+
+// A header field is a struct of its code and a variant holding the value.
+fn put_field(message: &mut Vec<u8>, code: u8, signature: u8, value: &str) {
+    message.resize(align(message.len(), 8), 0);
+    message.extend_from_slice(&[code, 1, signature, 0]);
+    match signature {
+        b'g' => {
+            message.push(value.len() as u8);
+            message.extend_from_slice(value.as_bytes());
+            message.push(0);
+        }
+        _ => put_string(message, value),
+    }
+}
+
+fn read_line(stream: &mut UnixStream) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while line.last() != Some(&b'\n') {
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHOD_RETURN: u8 = 2;
+
+    // A little-endian message with the usual header fields and one boolean
+    // argument.
+    fn message(kind: u8, interface: &str, member: &str, arg: bool) -> Vec<u8> {
+        let mut message = vec![b'l', kind, 0, 1];
+        put_u32(&mut message, 4);
+        put_u32(&mut message, 1);
+        put_u32(&mut message, 0);
+        put_field(&mut message, FIELD_PATH, b'o', "/org/freedesktop/login1");
+        put_field(&mut message, FIELD_INTERFACE, b's', interface);
+        put_field(&mut message, FIELD_MEMBER, b's', member);
+        put_field(&mut message, FIELD_SIGNATURE, b'g', "b");
+        let fields_length = (message.len() - 16) as u32;
+        message[12..16].copy_from_slice(&fields_length.to_le_bytes());
+        message.resize(align(message.len(), 8), 0);
+        put_u32(&mut message, arg as u32);
+        message
+    }
+
+    fn parse(message: &[u8]) -> io::Result<Signal> {
+        let fields_length = u32::from_le_bytes(message[12..16].try_into().unwrap());
+        parse_signal(message, fields_length as usize, false)
+    }
+
+    #[test]
+    fn parses_a_signal() {
+        let signal = parse(&message(SIGNAL, "org.example", "Sleep", false)).unwrap();
+        assert_eq!(signal.interface, "org.example");
+        assert_eq!(signal.member, "Sleep");
+        assert_eq!(signal.bool_arg(), Some(false));
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let message = message(SIGNAL, "org.example", "Sleep", true);
+        for length in 16..message.len() - 4 {
+            assert!(parse(&message[..length]).is_err(), "{}", length);
+        }
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_end() {
+        let signal = message(SIGNAL, "org.example", "Sleep", true);
+
+        // Length of the interface name.
+        let name = signal
+            .windows(11)
+            .position(|window| window == b"org.example")
+            .unwrap();
+        let mut broken = signal.clone();
+        broken[name - 4..name].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse(&broken).is_err());
+
+        // Length of all the header fields.
+        let mut broken = signal.clone();
+        broken[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse(&broken).is_err());
+    }
+
+    #[test]
+    fn skips_to_the_next_signal() {
+        let (stream, mut bus_end) = UnixStream::pair().unwrap();
+        let mut bus = SystemBus { stream, serial: 0 };
+        bus_end
+            .write_all(&message(METHOD_RETURN, "org.example", "Hello", true))
+            .unwrap();
+        bus_end
+            .write_all(&message(SIGNAL, "org.example", "Sleep", true))
+            .unwrap();
+        let signal = bus.next_signal().unwrap();
+        assert_eq!(signal.member, "Sleep");
+        assert_eq!(signal.bool_arg(), Some(true));
+    }
+
+    #[test]
+    fn refuses_messages_above_the_maximum_length() {
+        let (stream, mut bus_end) = UnixStream::pair().unwrap();
+        let mut bus = SystemBus { stream, serial: 0 };
+        let mut message = message(SIGNAL, "org.example", "Sleep", true);
+        message[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        bus_end.write_all(&message).unwrap();
+        assert!(bus.next_signal().is_err());
+    }
+}
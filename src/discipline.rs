@@ -0,0 +1,289 @@
+use crate::clock::{seconds, SystemClock, MAX_FREQUENCY_PPM};
+use chrono::{DateTime, Utc};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// Below this many seconds between updates the offsets are dominated by network
+// jitter and only the phase-locked loop is used; above it, the frequency-locked
+// loop takes over, as in ntpd.
+const ALLAN_INTERCEPT_SECS: f64 = 1024.0;
+const FLL_GAIN: f64 = 0.25;
+// Time constant of the phase-locked loop, in multiples of the update interval.
+const PLL_TIME_CONSTANT: f64 = 4.0;
+
+/// Estimates the frequency error of the local oscillator from successive
+/// offsets and corrects it in the kernel, so the clock stays close to the
+/// server between syncs instead of drifting off again.
+pub struct Discipline {
+    frequency_ppm: f64,
+    // When the current frequency started being measured, with the uncertainty
+    // of that sample, and the corrections made since.
+    measuring_since: Option<(DateTime<Utc>, chrono::Duration)>,
+    corrected: chrono::Duration,
+    drift_file: Option<PathBuf>,
+}
+
+impl Discipline {
+    /// Starts from the frequency saved in `drift_file`, or from whatever the
+    /// kernel is using when there is none yet.
+    pub fn new(clock: &mut dyn SystemClock, drift_file: Option<PathBuf>) -> Result<Self, String> {
+        let saved = match &drift_file {
+            Some(path) => read_drift_file(path)?,
+            None => None,
+        };
+        let frequency_ppm = match saved {
+            Some(ppm) => {
+                clock.set_frequency(ppm)?;
+                println!("Restored clock frequency of {:.3} ppm", ppm);
+                ppm
+            }
+            None => clock.frequency()?,
+        };
+
+        Ok(Discipline {
+            frequency_ppm,
+            measuring_since: None,
+            corrected: chrono::Duration::zero(),
+            drift_file,
+        })
+    }
+
+    /// Feeds the offset measured this sync, which is about to be corrected,
+    /// and its uncertainty, and returns the new frequency. Only failing to set
+    /// it is an error.
+    ///
+    /// Every sync brings the offset back to zero, so the corrections add up to
+    /// what the current frequency got wrong. Each one also undoes the error of
+    /// the sample before it, so however many are added up, the sum is only off
+    /// by the uncertainty of the first and the latest sample. The frequency is
+    /// left alone until the sum stands out from that, rather than mistaking
+    /// noise like daytime's whole seconds for hundreds of ppm.
+    pub fn update(
+        &mut self,
+        clock: &mut dyn SystemClock,
+        offset: chrono::Duration,
+        uncertainty: chrono::Duration,
+    ) -> Result<f64, String> {
+        let now = clock.now();
+        let Some((since, first_uncertainty)) = self.measuring_since else {
+            self.measuring_since = Some((now, uncertainty));
+            self.corrected = chrono::Duration::zero();
+            return Ok(self.frequency_ppm);
+        };
+        self.corrected += offset;
+        let interval = seconds(now - since);
+        if self.corrected.abs() <= first_uncertainty + uncertainty || interval <= 0.0 {
+            return Ok(self.frequency_ppm);
+        }
+        let error_ppm = seconds(self.corrected) / interval * 1e6;
+        self.measuring_since = Some((now, uncertainty));
+        self.corrected = chrono::Duration::zero();
+
+        let pll = error_ppm / (PLL_TIME_CONSTANT * PLL_TIME_CONSTANT);
+        let fll = match interval >= ALLAN_INTERCEPT_SECS {
+            true => error_ppm * FLL_GAIN,
+            false => 0.0,
+        };
+        self.frequency_ppm =
+            (self.frequency_ppm + pll + fll).clamp(-MAX_FREQUENCY_PPM, MAX_FREQUENCY_PPM);
+        clock.set_frequency(self.frequency_ppm)?;
+
+        // Losing the estimate only makes the next start take longer to settle,
+        // so the clock is kept disciplined either way.
+        if let Some(path) = &self.drift_file {
+            if let Err(e) = fs::write(path, format!("{:.3}\n", self.frequency_ppm)) {
+                println!("Error writing drift file {}: {}", path.display(), e);
+            }
+        }
+        Ok(self.frequency_ppm)
+    }
+
+    /// Forgets the previous offset, e.g. after the clock was stepped, so the
+    /// next interval isn't mistaken for frequency error.
+    pub fn reset(&mut self) {
+        self.measuring_since = None;
+    }
+}
+
+fn read_drift_file(path: &Path) -> Result<Option<f64>, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => contents
+            .trim()
+            .parse::<f64>()
+            .map(Some)
+            .map_err(|e| format!("Invalid drift file {}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!(
+            "Error reading drift file {}: {}",
+            path.display(),
+            e
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, source::ms};
+
+    fn clock(drift_ppm: f64) -> SimulatedClock {
+        SimulatedClock::new(Utc::now(), chrono::Duration::zero(), drift_ppm)
+    }
+
+    fn drift_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nist_time_sync-{}-{}", std::process::id(), name))
+    }
+
+    // Steps `clock` to samples of the true time `interval` apart, cycling
+    // through `noise` as their error, and returns the frequency the
+    // discipline ended up with.
+    fn sync(
+        clock: &mut SimulatedClock,
+        discipline: &mut Discipline,
+        syncs: usize,
+        interval: chrono::Duration,
+        noise: &[i64],
+        uncertainty: chrono::Duration,
+    ) -> f64 {
+        let mut frequency = clock.frequency().unwrap();
+        for sync in 0..syncs {
+            clock.advance(interval);
+            let noise = chrono::Duration::milliseconds(noise[sync % noise.len()]);
+            let offset = clock.true_time() - clock.now() + noise;
+            clock.step(offset).unwrap();
+            frequency = discipline.update(clock, offset, uncertainty).unwrap();
+        }
+        frequency
+    }
+
+    #[test]
+    fn starts_from_the_current_frequency_without_a_drift_file() {
+        let mut clock = clock(0.0);
+        clock.set_frequency(12.5).unwrap();
+        let path = drift_file("missing");
+        Discipline::new(&mut clock, Some(path.clone())).unwrap();
+        assert_eq!(clock.frequency(), Ok(12.5));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn restores_and_saves_the_frequency() {
+        let path = drift_file("saved");
+        fs::write(&path, "-7.250\n").unwrap();
+        let mut clock = clock(10.0);
+        let mut discipline = Discipline::new(&mut clock, Some(path.clone())).unwrap();
+        assert_eq!(clock.frequency(), Ok(-7.25));
+
+        let frequency = sync(
+            &mut clock,
+            &mut discipline,
+            2,
+            chrono::Duration::seconds(1024),
+            &[0],
+            ms(1),
+        );
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{:.3}\n", frequency)
+        );
+        assert!(frequency < -7.25);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_an_invalid_drift_file() {
+        let path = drift_file("invalid");
+        fs::write(&path, "fast\n").unwrap();
+        assert!(Discipline::new(&mut clock(0.0), Some(path.clone())).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keeps_disciplining_when_the_drift_file_cant_be_written() {
+        let path = drift_file("missing-directory").join("drift");
+        let mut clock = clock(-20.0);
+        let mut discipline = Discipline::new(&mut clock, Some(path)).unwrap();
+        let frequency = sync(
+            &mut clock,
+            &mut discipline,
+            2,
+            chrono::Duration::seconds(1024),
+            &[0],
+            ms(1),
+        );
+        assert!(frequency > 0.0 && frequency <= 20.0, "{}", frequency);
+        assert_eq!(clock.frequency(), Ok(frequency));
+    }
+
+    #[test]
+    fn learns_the_drift_of_the_clock() {
+        let mut clock = clock(50.0);
+        let mut discipline = Discipline::new(&mut clock, None).unwrap();
+        let frequency = sync(
+            &mut clock,
+            &mut discipline,
+            100,
+            chrono::Duration::seconds(1024),
+            &[5, -5],
+            ms(10),
+        );
+        assert!((frequency + 50.0).abs() < 1.0, "{}", frequency);
+    }
+
+    #[test]
+    fn learns_the_drift_through_whole_second_noise() {
+        let mut clock = clock(50.0);
+        let mut discipline = Discipline::new(&mut clock, None).unwrap();
+        let frequency = sync(
+            &mut clock,
+            &mut discipline,
+            200,
+            chrono::Duration::seconds(1024),
+            &[400, -400],
+            ms(500),
+        );
+        assert!((frequency + 50.0).abs() < 5.0, "{}", frequency);
+    }
+
+    #[test]
+    fn leaves_the_frequency_alone_with_noise_within_the_uncertainty() {
+        let mut clock = clock(0.0);
+        let mut discipline = Discipline::new(&mut clock, None).unwrap();
+        let frequency = sync(
+            &mut clock,
+            &mut discipline,
+            100,
+            chrono::Duration::seconds(64),
+            &[400, -400, 0],
+            ms(500),
+        );
+        assert_eq!(frequency, 0.0);
+    }
+
+    #[test]
+    fn starts_measuring_again_after_a_reset() {
+        let mut clock = clock(50.0);
+        let mut discipline = Discipline::new(&mut clock, None).unwrap();
+        sync(
+            &mut clock,
+            &mut discipline,
+            1,
+            chrono::Duration::seconds(1024),
+            &[0],
+            ms(1),
+        );
+        discipline.reset();
+        // A sample after the reset only starts a new measurement.
+        let frequency = sync(
+            &mut clock,
+            &mut discipline,
+            1,
+            chrono::Duration::seconds(1024),
+            &[0],
+            ms(1),
+        );
+        assert_eq!(frequency, 0.0);
+    }
+}
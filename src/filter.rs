@@ -0,0 +1,179 @@
+use crate::{
+    clock::SystemClock,
+    pool::{QuerySpacing, MIN_QUERY_SPACING},
+    source::{Sample, SourceError, TimeSource},
+};
+use clap::ValueEnum;
+
+/// How a burst of samples is reduced to one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ClockFilter {
+    /// The sample with the shortest round trip, the least affected by
+    /// queueing delays
+    MinDelay,
+    /// The sample with the median offset, which ignores outliers
+    Median,
+}
+
+impl ClockFilter {
+    fn apply(self, mut samples: Vec<Sample>) -> Option<Sample> {
+        match self {
+            ClockFilter::MinDelay => samples.into_iter().min_by_key(|sample| sample.delay()),
+            ClockFilter::Median => {
+                samples.sort_by_key(|sample| sample.offset());
+                let middle = (samples.len().max(1) - 1) / 2;
+                samples.into_iter().nth(middle)
+            }
+        }
+    }
+}
+
+/// Takes several samples from a source per sync and only passes on the one
+/// picked by the filter.
+pub struct BurstSource {
+    source: Box<dyn TimeSource>,
+    count: u32,
+    filter: ClockFilter,
+    // Also applies to sources without a pool of their own, like NTP.
+    spacing: QuerySpacing,
+}
+
+impl BurstSource {
+    pub fn new(source: Box<dyn TimeSource>, count: u32, filter: ClockFilter) -> Self {
+        BurstSource {
+            source,
+            count,
+            filter,
+            spacing: QuerySpacing::new(MIN_QUERY_SPACING),
+        }
+    }
+}
+
+impl TimeSource for BurstSource {
+    fn sample(&mut self, clock: &dyn SystemClock) -> Result<Sample, SourceError> {
+        let mut samples = Vec::new();
+        let mut last_error = SourceError::from("Empty burst");
+        for _ in 0..self.count {
+            self.spacing.wait();
+            match self.source.sample(clock) {
+                Ok(sample) => samples.push(sample),
+                Err(e) => last_error = e,
+            }
+        }
+
+        self.filter.apply(samples).ok_or(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::OsClock,
+        source::{ms, test_sample, MockSource},
+    };
+    use chrono::Utc;
+    use std::time::{Duration, Instant};
+
+    // A sample `offset` milliseconds off with a round trip of `delay`.
+    fn sample(offset: i64, delay: u64) -> Result<Sample, SourceError> {
+        let mut sample = test_sample(&format!("{} ms", offset), Utc::now(), ms(offset), ms(10));
+        sample.received = sample.sent + Duration::from_millis(delay);
+        Ok(sample)
+    }
+
+    fn burst(replies: Vec<Result<Sample, SourceError>>, filter: ClockFilter) -> BurstSource {
+        BurstSource {
+            count: replies.len() as u32,
+            source: Box::new(MockSource::new(replies)),
+            filter,
+            spacing: QuerySpacing::new(Duration::ZERO),
+        }
+    }
+
+    #[test]
+    fn keeps_the_sample_with_the_shortest_round_trip() {
+        let replies = vec![sample(30, 80), sample(10, 20), sample(-40, 50)];
+        let sample = burst(replies, ClockFilter::MinDelay)
+            .sample(&OsClock)
+            .unwrap();
+        assert_eq!(sample.source, "10 ms");
+    }
+
+    #[test]
+    fn keeps_the_median_of_an_odd_burst() {
+        let replies = vec![
+            sample(30, 0),
+            sample(-500, 0),
+            sample(10, 0),
+            sample(900, 0),
+            sample(20, 0),
+        ];
+        let sample = burst(replies, ClockFilter::Median)
+            .sample(&OsClock)
+            .unwrap();
+        assert_eq!(sample.offset(), ms(20));
+    }
+
+    #[test]
+    fn keeps_the_lower_median_of_an_even_burst() {
+        let replies = vec![
+            sample(30, 0),
+            sample(-500, 0),
+            sample(10, 0),
+            sample(900, 0),
+        ];
+        let sample = burst(replies, ClockFilter::Median)
+            .sample(&OsClock)
+            .unwrap();
+        assert_eq!(sample.offset(), ms(10));
+    }
+
+    #[test]
+    fn filters_the_samples_that_arrived() {
+        let replies = vec![
+            Err("lost".into()),
+            sample(30, 80),
+            Err("lost".into()),
+            sample(10, 20),
+        ];
+        let sample = burst(replies, ClockFilter::MinDelay)
+            .sample(&OsClock)
+            .unwrap();
+        assert_eq!(sample.source, "10 ms");
+    }
+
+    #[test]
+    fn fails_when_every_sample_fails() {
+        let replies = vec![
+            Err("lost".into()),
+            Err(SourceError::Timeout("timed out".to_string())),
+        ];
+        let error = burst(replies, ClockFilter::Median)
+            .sample(&OsClock)
+            .unwrap_err();
+        assert_eq!(error, SourceError::Timeout("timed out".to_string()));
+    }
+
+    #[test]
+    fn spaces_the_queries_of_a_burst() {
+        let mut source = burst(
+            vec![sample(0, 0), sample(0, 0), sample(0, 0)],
+            ClockFilter::MinDelay,
+        );
+        source.spacing = QuerySpacing::new(Duration::from_millis(50));
+        let start = Instant::now();
+        source.sample(&OsClock).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn spaces_bursts_by_the_pool_rule() {
+        let source = BurstSource::new(
+            Box::new(MockSource::new(Vec::new())),
+            2,
+            ClockFilter::MinDelay,
+        );
+        assert_eq!(source.spacing.interval(), MIN_QUERY_SPACING);
+    }
+}
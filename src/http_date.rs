@@ -0,0 +1,419 @@
+use crate::{
+    clock::SystemClock,
+    net, selection,
+    source::{Sample, SourceError, TimeSource},
+};
+use chrono::{DateTime, Utc};
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::{
+    io::{Read, Write},
+    net::ToSocketAddrs,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const MAX_HEADER_LEN: usize = 16 * 1024;
+
+/// Range of clock offsets consistent with one or more Date headers.
+#[derive(Debug, Clone, Copy)]
+struct OffsetBounds {
+    low: chrono::Duration,
+    high: chrono::Duration,
+}
+
+impl OffsetBounds {
+    fn offset(&self) -> chrono::Duration {
+        self.low + (self.high - self.low) / 2
+    }
+
+    fn uncertainty(&self) -> chrono::Duration {
+        (self.high - self.low) / 2
+    }
+
+    fn intersect(&self, other: &OffsetBounds) -> Option<OffsetBounds> {
+        let bounds = OffsetBounds {
+            low: self.low.max(other.low),
+            high: self.high.min(other.high),
+        };
+        match bounds.low <= bounds.high {
+            true => Some(bounds),
+            false => None,
+        }
+    }
+}
+
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Self, String> {
+        let (tls, rest) = match url.split_once("://") {
+            Some(("https", rest)) => (true, rest),
+            Some(("http", rest)) => (false, rest),
+            _ => return Err(format!("Unsupported URL {}, expected http or https", url)),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let default_port = match tls {
+            true => 443,
+            false => 80,
+        };
+        let (host, port) = net::split_host_port(authority, default_port)?;
+        Ok(Url {
+            tls,
+            host,
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Estimates the clock offset from the `Date` headers of a list of URLs.
+///
+/// Each reply only says which second the server was in, so every request is
+/// turned into an interval of possible offsets and the intervals of each
+/// server are intersected. After the first reply, requests are sent when our
+/// current estimate says the server ticks over to a new second, so the
+/// answers narrow the interval well below the one-second resolution of the
+/// header. The servers' intervals are then combined by majority, the same way
+/// as separate sources.
+pub struct HttpSource {
+    urls: Vec<String>,
+    rounds: u32,
+    tls_config: Arc<ClientConfig>,
+    timeouts: net::Timeouts,
+}
+
+impl HttpSource {
+    pub fn new(urls: &[String], rounds: u32, timeouts: net::Timeouts) -> Result<Self, String> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let tls_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            })
+            .with_no_client_auth();
+
+        Ok(HttpSource {
+            urls: urls.to_vec(),
+            rounds: rounds.max(1),
+            tls_config: Arc::new(tls_config),
+            timeouts,
+        })
+    }
+}
+
+impl TimeSource for HttpSource {
+    fn sample(&mut self, clock: &dyn SystemClock) -> Result<Sample, SourceError> {
+        // Every server only narrows its own interval, the servers are
+        // compared at the end so one that is wrong can't push out the rest.
+        let mut probes: Vec<Option<Probe>> = self.urls.iter().map(|_| None).collect();
+        let mut last_error = SourceError::from("No HTTP servers configured");
+        for _ in 0..self.rounds {
+            for (url, narrowed) in self.urls.iter().zip(&mut probes) {
+                if let Some(narrowed) = narrowed {
+                    wait_for_second_boundary(clock, narrowed.bounds.offset());
+                }
+                let probe = match probe(url, &self.tls_config, self.timeouts, clock) {
+                    Ok(probe) => probe,
+                    Err(e) => {
+                        println!("Skipping HTTP server {}: {}", url, e);
+                        last_error = e;
+                        continue;
+                    }
+                };
+                let bounds = match narrowed {
+                    None => probe.bounds,
+                    Some(narrowed) => match narrowed.bounds.intersect(&probe.bounds) {
+                        Some(bounds) => bounds,
+                        None => {
+                            println!(
+                                "Skipping reply from HTTP server {}: Date header disagrees with its earlier ones",
+                                url
+                            );
+                            continue;
+                        }
+                    },
+                };
+                *narrowed = Some(Probe { bounds, ..probe });
+            }
+        }
+
+        let local_time = clock.now();
+        let samples: Vec<Sample> = self
+            .urls
+            .iter()
+            .zip(probes)
+            .filter_map(|(url, probe)| {
+                let probe = probe?;
+                Some(Sample {
+                    source: url.clone(),
+                    server_time: local_time + probe.bounds.offset(),
+                    local_time,
+                    sent: probe.sent,
+                    received: probe.received,
+                    uncertainty: probe.bounds.uncertainty(),
+                })
+            })
+            .collect();
+        if samples.is_empty() {
+            return Err(last_error);
+        }
+
+        let selection = selection::select(&samples)?;
+        for url in &selection.falsetickers {
+            println!(
+                "Ignoring HTTP server {}: Date header disagrees with the other servers",
+                url
+            );
+        }
+        let truechimer = samples
+            .iter()
+            .rfind(|sample| !selection.falsetickers.contains(&sample.source))
+            .unwrap_or(&samples[0]);
+        Ok(Sample {
+            source: format!("http {}", self.urls.join(", ")),
+            server_time: local_time + selection.offset,
+            local_time,
+            sent: truechimer.sent,
+            received: truechimer.received,
+            uncertainty: selection.uncertainty,
+        })
+    }
+}
+
+// Sleeps until `clock`, corrected by `offset`, is at the next whole second.
+fn wait_for_second_boundary(clock: &dyn SystemClock, offset: chrono::Duration) {
+    let server_now = clock.now() + offset;
+    let nanos = server_now.timestamp_subsec_nanos() as u64 % 1_000_000_000;
+    thread::sleep(Duration::from_nanos(1_000_000_000 - nanos));
+}
+
+struct Probe {
+    bounds: OffsetBounds,
+    sent: Instant,
+    received: Instant,
+}
+
+// Sends a HEAD request and returns the offsets consistent with its Date
+// header: the server stamped a time in [date, date + 1s) somewhere between
+// our send and receive instants, as read from `clock`.
+fn probe(
+    url: &str,
+    tls_config: &Arc<ClientConfig>,
+    timeouts: net::Timeouts,
+    clock: &dyn SystemClock,
+) -> Result<Probe, SourceError> {
+    let url = Url::parse(url)?;
+    let addrs = (url.host.as_str(), url.port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Error resolving {}: {}", url.host, e))?;
+    let mut socket = Err(SourceError::from(format!(
+        "No addresses found for {}",
+        url.host
+    )));
+    for addr in addrs {
+        socket = net::tcp_connect(addr, timeouts).map_err(net::io_error);
+        if socket.is_ok() {
+            break;
+        }
+    }
+    let mut socket = socket?;
+
+    let request = format!(
+        "HEAD {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}/{}\r\nConnection: close\r\n\r\n",
+        url.path,
+        url.host,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let exchange = match url.tls {
+        true => {
+            let server_name = ServerName::try_from(url.host.clone())
+                .map_err(|e| format!("Invalid server name {}: {}", url.host, e))?;
+            let mut connection = ClientConnection::new(tls_config.clone(), server_name)
+                .map_err(|e| e.to_string())?;
+            // Finish the handshake first so it doesn't count as network delay.
+            while connection.is_handshaking() {
+                connection
+                    .complete_io(&mut socket)
+                    .map_err(|e| match net::io_error(e) {
+                        SourceError::Failed(message) => format!("TLS error: {}", message).into(),
+                        timeout => timeout,
+                    })?;
+            }
+            exchange(&mut StreamOwned::new(connection, socket), &request, clock)?
+        }
+        false => exchange(&mut socket, &request, clock)?,
+    };
+
+    let date = exchange
+        .headers
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("date"))
+        .map(|(_, value)| value.trim())
+        .ok_or("Response has no Date header")?;
+    let date = DateTime::parse_from_rfc2822(date)
+        .map_err(|e| format!("Invalid Date header {:?}: {}", date, e))?
+        .with_timezone(&Utc);
+
+    Ok(Probe {
+        bounds: OffsetBounds {
+            low: date - exchange.received_at,
+            high: date + chrono::Duration::seconds(1) - exchange.sent_at,
+        },
+        sent: exchange.sent,
+        received: exchange.received,
+    })
+}
+
+struct Exchange {
+    sent: Instant,
+    sent_at: DateTime<Utc>,
+    received: Instant,
+    received_at: DateTime<Utc>,
+    headers: String,
+}
+
+fn exchange(
+    stream: &mut (impl Read + Write),
+    request: &str,
+    clock: &dyn SystemClock,
+) -> Result<Exchange, SourceError> {
+    let sent = Instant::now();
+    let sent_at = clock.now();
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|_| stream.flush())
+        .map_err(net::io_error)?;
+
+    let mut response = Vec::new();
+    let mut received = None;
+    let mut buffer = [0u8; 4096];
+    while !response.windows(4).any(|window| window == b"\r\n\r\n") {
+        let bytes_read = stream.read(&mut buffer).map_err(net::io_error)?;
+        received.get_or_insert_with(|| (Instant::now(), clock.now()));
+        if bytes_read == 0 || response.len() > MAX_HEADER_LEN {
+            break;
+        }
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+
+    let (received, received_at) = received.ok_or("Empty HTTP response")?;
+    Ok(Exchange {
+        sent,
+        sent_at,
+        received,
+        received_at,
+        headers: String::from_utf8_lossy(&response).into_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::OsClock;
+    use std::net::TcpListener;
+
+    fn timeouts() -> net::Timeouts {
+        net::Timeouts {
+            connect: Duration::from_secs(2),
+            read: Duration::from_secs(2),
+        }
+    }
+
+    // An HTTP server whose Date header is `date()` at the time of each of
+    // `requests` requests.
+    fn stand_in_server(
+        requests: usize,
+        date: impl Fn() -> DateTime<Utc> + Send + 'static,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for _ in 0..requests {
+                let (mut socket, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let bytes_read = socket.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..bytes_read]);
+                }
+                let date = date().format("%a, %d %b %Y %H:%M:%S GMT");
+                let response = format!("HTTP/1.1 200 OK\r\nDate: {}\r\n\r\n", date);
+                socket.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn intersects_date_headers() {
+        let offset = chrono::Duration::seconds(30);
+        let url = stand_in_server(3, move || Utc::now() + offset);
+        let mut source = HttpSource::new(&[url], 3, timeouts()).unwrap();
+        let sample = source.sample(&OsClock).unwrap();
+        assert!((sample.offset() - offset).abs() <= sample.uncertainty);
+        assert!(sample.uncertainty < chrono::Duration::milliseconds(500));
+    }
+
+    fn liar() -> String {
+        let fixed = DateTime::parse_from_rfc2822("Mon, 01 Jan 2001 00:00:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        stand_in_server(1, move || fixed)
+    }
+
+    #[test]
+    fn drops_a_server_that_disagrees() {
+        let good = stand_in_server(1, Utc::now);
+        let other = stand_in_server(1, Utc::now);
+        let mut source = HttpSource::new(&[good, liar(), other], 1, timeouts()).unwrap();
+        let sample = source.sample(&OsClock).unwrap();
+        assert!(sample.offset().abs() <= sample.uncertainty);
+        assert!(sample.uncertainty <= chrono::Duration::seconds(1));
+    }
+
+    #[test]
+    fn outvotes_a_liar_listed_first() {
+        let good = stand_in_server(1, Utc::now);
+        let other = stand_in_server(1, Utc::now);
+        let mut source = HttpSource::new(&[liar(), good, other], 1, timeouts()).unwrap();
+        let sample = source.sample(&OsClock).unwrap();
+        assert!(sample.offset().abs() <= sample.uncertainty);
+        assert!(sample.uncertainty <= chrono::Duration::seconds(1));
+    }
+
+    #[test]
+    fn refuses_to_pick_between_two_servers_that_disagree() {
+        let good = stand_in_server(1, Utc::now);
+        let mut source = HttpSource::new(&[liar(), good], 1, timeouts()).unwrap();
+        assert!(source.sample(&OsClock).is_err());
+    }
+
+    #[test]
+    fn rejects_a_reply_without_date() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut buffer = [0u8; 1024];
+            let _ = socket.read(&mut buffer).unwrap();
+            socket.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+        });
+        let mut source = HttpSource::new(&[url], 1, timeouts()).unwrap();
+        assert!(source.sample(&OsClock).is_err());
+    }
+}
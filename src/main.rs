@@ -1,95 +1,537 @@
+mod clock;
+mod daytime;
 #[cfg(target_os = "linux")]
-use chrono::Local;
-use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
-use clap::Parser;
-use std::{io::Read, net::TcpStream, thread, time::Duration};
+mod dbus;
+mod discipline;
+mod filter;
+mod http_date;
+mod net;
+#[cfg(target_os = "linux")]
+mod network;
+mod ntp;
+mod nts;
+mod poll;
+mod pool;
+mod retry;
+mod rfc868;
+mod roughtime;
+mod schedule;
+mod selection;
+mod source;
+#[cfg(target_os = "linux")]
+mod suspend;
+
+use chrono::{DateTime, Local, Utc};
+use clap::{Parser, ValueEnum};
+use clock::{OsClock, SystemClock};
+use daytime::{Compensation, DaytimeSource, HealthPolicy};
+use discipline::Discipline;
+use filter::{BurstSource, ClockFilter};
+use poll::PollInterval;
+use pool::{ServerOrder, ServerPool};
+use schedule::Scheduler;
+use selection::Selection;
+use source::{SourceError, TimeSource};
+#[cfg(target_os = "windows")]
+use std::thread;
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
 
 #[cfg(target_os = "windows")]
 const SERVICE_NAME: &str = "NISTTimeSync";
-const NIST_TIME_SERVER: &str = "time.nist.gov:13";
+// NIST's round-robin name, which spreads clients over its servers, then the
+// published servers in Gaithersburg, Fort Collins (WWV) and Boulder in case
+// it lands on one that is down.
+const NIST_TIME_SERVERS: [&str; 13] = [
+    "time.nist.gov:13",
+    "time-a-g.nist.gov:13",
+    "time-b-g.nist.gov:13",
+    "time-c-g.nist.gov:13",
+    "time-d-g.nist.gov:13",
+    "time-a-wwv.nist.gov:13",
+    "time-b-wwv.nist.gov:13",
+    "time-c-wwv.nist.gov:13",
+    "time-d-wwv.nist.gov:13",
+    "time-a-b.nist.gov:13",
+    "time-b-b.nist.gov:13",
+    "time-c-b.nist.gov:13",
+    "time-d-b.nist.gov:13",
+];
+const NIST_NTP_SERVER: &str = "time.nist.gov:123";
+const NIST_RFC868_SERVER: &str = "time.nist.gov:37";
+const DEFAULT_NTS_SERVER: &str = "time.cloudflare.com:4460";
+const DEFAULT_HTTP_URLS: [&str; 3] = [
+    "https://www.nist.gov/",
+    "https://www.google.com/",
+    "https://www.cloudflare.com/",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Source {
+    /// NIST daytime protocol over TCP port 13
+    Daytime,
+    /// SNTP/NTPv4 over UDP
+    Ntp,
+    /// RFC 868 Time protocol on port 37
+    Time,
+    /// NTPv4 authenticated with Network Time Security
+    Nts,
+    /// Date header of HTTP(S) responses
+    Http,
+}
 
 #[derive(Parser)]
 #[command(version, author = "André Azevedo")]
 struct Args {
-    #[arg(short = 'i', long = "interval", default_value = "60")]
-    interval: u64,
+    /// Time between syncs, such as 90s, 15m or 2h; a bare number is minutes
+    #[arg(short = 'i', long = "interval", default_value = "60", value_parser = schedule::parse_interval)]
+    interval: Duration,
+    /// Adapt the interval between syncs to how stable the clock is, within
+    /// --min-poll and --max-poll, instead of using --interval
+    #[arg(long = "adaptive")]
+    adaptive: bool,
+    /// Shortest interval in adaptive mode, as a power of two seconds like
+    /// NTP's minpoll
+    #[arg(long = "min-poll", default_value = "6", value_parser = clap::value_parser!(u8).range(3..=17))]
+    min_poll: u8,
+    /// Longest interval in adaptive mode, as a power of two seconds like
+    /// NTP's maxpoll
+    #[arg(long = "max-poll", default_value = "10", value_parser = clap::value_parser!(u8).range(3..=17))]
+    max_poll: u8,
+    /// Protocol used to fetch the time, may be repeated to combine several
+    #[arg(
+        long = "source",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "daytime"
+    )]
+    sources: Vec<Source>,
+    /// Extra daytime server tried before NIST's list, may be repeated
+    #[arg(long = "server")]
+    servers: Vec<String>,
+    /// Order in which the daytime servers are tried
+    #[arg(long = "server-order", value_enum, default_value = "in-order")]
+    server_order: ServerOrder,
+    /// NTP server used by the ntp source, may be repeated
+    #[arg(long = "ntp-server", default_value = NIST_NTP_SERVER)]
+    ntp_servers: Vec<String>,
+    /// RFC 868 server used by the time source
+    #[arg(long = "time-server", default_value = NIST_RFC868_SERVER)]
+    time_server: String,
+    /// Transport used by the time source
+    #[arg(long = "time-transport", value_enum, default_value = "tcp")]
+    time_transport: rfc868::Transport,
+    /// NTS-KE server used by the nts source
+    #[arg(long = "nts-server", default_value = DEFAULT_NTS_SERVER)]
+    nts_server: String,
+    /// Additional PEM CA certificates trusted for the NTS-KE server
+    #[arg(long = "nts-ca")]
+    nts_ca: Option<PathBuf>,
+    /// URLs queried by the http source, may be repeated
+    #[arg(long = "http-url", default_values = DEFAULT_HTTP_URLS)]
+    http_urls: Vec<String>,
+    /// Number of passes over the HTTP URLs, each one narrows the estimate
+    #[arg(long = "http-rounds", default_value = "4")]
+    http_rounds: u32,
+    /// Roughtime server as host:port=base64key used to sanity-check the
+    /// result before touching the clock, may be repeated
+    #[arg(long = "roughtime")]
+    roughtime: Vec<roughtime::RoughtimeServer>,
+    /// How to treat servers reporting a non-zero health digit: reject, warn,
+    /// or the highest health level to accept
+    #[arg(long = "health-policy", default_value = "reject")]
+    health_policy: HealthPolicy,
+    /// Network delay compensation: NIST's msADV estimate or our measured round trip
+    #[arg(long = "compensation", value_enum, default_value = "nist")]
+    compensation: Compensation,
+    /// Samples taken from each source per sync, 4 seconds apart
+    #[arg(long = "burst", default_value = "1")]
+    burst: u32,
+    /// How a burst is reduced to a single sample
+    #[arg(long = "filter", value_enum, default_value = "min-delay")]
+    filter: ClockFilter,
+    /// Discard samples whose network round trip is above this many
+    /// milliseconds, at most a day
+    #[arg(long = "max-delay", value_parser = clap::value_parser!(u64).range(..=86_400_000))]
+    max_delay: Option<u64>,
+    /// Seconds to wait for a server to accept a connection
+    #[arg(long = "connect-timeout", default_value = "5")]
+    connect_timeout: u64,
+    /// Seconds to wait for a server to answer
+    #[arg(long = "read-timeout", default_value = "5")]
+    read_timeout: u64,
+    /// Slew small offsets gradually instead of stepping the clock
+    #[arg(long = "slew")]
+    slew: bool,
+    /// Offsets larger than this many milliseconds are still stepped in slew
+    /// mode, at most a day
+    #[arg(long = "step-threshold", default_value = "128", value_parser = clap::value_parser!(u64).range(..=86_400_000))]
+    step_threshold: u64,
+    /// Estimate and correct the frequency error of the local clock
+    #[arg(long = "discipline")]
+    discipline: bool,
+    /// File the estimated frequency is saved to and restored from at startup
+    #[arg(long = "drift-file")]
+    drift_file: Option<PathBuf>,
     #[arg(long = "install")]
     install: bool,
     #[arg(long = "uninstall")]
     uninstall: bool,
 }
 
-#[cfg(target_os = "windows")]
-fn set_system_time(datetime: DateTime<Utc>) -> Result<i32, String> {
-    use chrono::{Datelike, Timelike};
-    use winapi::{
-        shared::minwindef::{FALSE, WORD},
-        um::{minwinbase::SYSTEMTIME, sysinfoapi::SetSystemTime},
-    };
+impl Args {
+    // Longest time between two syncs.
+    fn max_interval(&self) -> Duration {
+        match self.adaptive {
+            true => poll::interval(self.max_poll),
+            false => self.interval,
+        }
+    }
 
-    let system_time = SYSTEMTIME {
-        wYear: datetime.year() as WORD,
-        wMonth: datetime.month() as WORD,
-        wDay: datetime.day() as WORD,
-        wHour: datetime.hour() as WORD,
-        wMinute: datetime.minute() as WORD,
-        wSecond: datetime.second() as WORD,
-        wMilliseconds: datetime.timestamp_subsec_millis() as WORD,
-        wDayOfWeek: 0,
-    };
+    fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout)
+    }
 
-    let result = unsafe { SetSystemTime(&system_time) };
-    match result {
-        FALSE => Err("Error setting system time".into()),
-        _ => Ok(0),
+    fn timeouts(&self) -> net::Timeouts {
+        net::Timeouts {
+            connect: Duration::from_secs(self.connect_timeout),
+            read: self.read_timeout(),
+        }
+    }
+}
+
+// How often the clock is synced, for the startup message.
+fn schedule(args: &Args) -> String {
+    match args.adaptive {
+        true => format!(
+            "every {} to {} seconds depending on how stable the clock is",
+            poll::interval(args.min_poll).as_secs(),
+            poll::interval(args.max_poll).as_secs()
+        ),
+        false => format!("every {}", schedule::format_interval(args.interval)),
     }
 }
 
-fn get_nist_server_time() -> Result<String, std::io::Error> {
-    let mut stream = TcpStream::connect(NIST_TIME_SERVER)?;
-    let mut buffer = [0u8; 256];
-    let bytes_read = stream.read(&mut buffer)?;
+fn daytime_servers(args: &Args) -> Vec<String> {
+    args.servers
+        .iter()
+        .cloned()
+        .chain(NIST_TIME_SERVERS.iter().map(|server| server.to_string()))
+        .collect()
+}
 
-    let time_string = String::from_utf8_lossy(&buffer[..bytes_read])
-        .trim()
-        .to_string();
+// One source per configured server, so each gets a vote in the selection.
+fn build_sources(args: &Args) -> Result<Vec<Box<dyn TimeSource>>, String> {
+    let mut sources: Vec<Box<dyn TimeSource>> = Vec::new();
+    let mut seen = Vec::new();
+    for &source in &args.sources {
+        if seen.contains(&source) {
+            continue;
+        }
+        seen.push(source);
+        match source {
+            Source::Daytime => sources.push(Box::new(DaytimeSource::new(
+                ServerPool::new(&daytime_servers(args), args.server_order),
+                args.health_policy,
+                args.compensation,
+                args.timeouts(),
+            ))),
+            Source::Ntp => {
+                for server in &args.ntp_servers {
+                    sources.push(Box::new(ntp::NtpSource::new(server, args.read_timeout())));
+                }
+            }
+            Source::Time => sources.push(Box::new(rfc868::Rfc868Source::new(
+                &args.time_server,
+                args.time_transport,
+                args.timeouts(),
+            ))),
+            Source::Nts => sources.push(Box::new(nts::NtsClient::new(
+                &args.nts_server,
+                args.nts_ca.as_deref(),
+                args.timeouts(),
+            )?)),
+            Source::Http => sources.push(Box::new(http_date::HttpSource::new(
+                &args.http_urls,
+                args.http_rounds,
+                args.timeouts(),
+            )?)),
+        }
+    }
 
-    Ok(time_string)
+    Ok(match args.burst {
+        0 | 1 => sources,
+        count => sources
+            .into_iter()
+            .map(|source| {
+                Box::new(BurstSource::new(source, count, args.filter)) as Box<dyn TimeSource>
+            })
+            .collect(),
+    })
 }
 
-fn parse_nist_response(response: &str) -> DateTime<Utc> {
-    let fields: Vec<&str> = response.split_whitespace().collect();
-    let date = fields[1];
-    let time = fields[2];
-    let year = date[0..2].parse::<i32>().unwrap() + 2000;
-    let month = date[3..5].parse::<u32>().unwrap();
-    let day = date[6..8].parse::<u32>().unwrap();
-    let hour = time[0..2].parse::<u32>().unwrap();
-    let minute = time[3..5].parse::<u32>().unwrap();
-    let second = time[6..8].parse::<u32>().unwrap();
-    let milisseconds: f64 = fields[6].parse::<f64>().unwrap();
-    let naive = NaiveDateTime::new(
-        NaiveDate::from_ymd_opt(year, month, day).unwrap(),
-        NaiveTime::from_hms_opt(hour, minute, second).unwrap(),
-    );
-    let datetime = parse_datetime(naive);
-    datetime + chrono::Duration::milliseconds(milisseconds as i64)
+// Takes a sample from every source and returns the offset of the true time
+// from `clock` that the majority of them agree on.
+fn query_time(
+    sources: &mut [Box<dyn TimeSource>],
+    args: &Args,
+    clock: &dyn SystemClock,
+) -> Result<Selection, SourceError> {
+    let mut samples = Vec::new();
+    let mut last_error = SourceError::from("No time sources configured");
+    let combining = sources.len() > 1;
+    for source in sources {
+        let sample = match source.sample(clock) {
+            Ok(sample) => sample,
+            Err(e) => {
+                if combining {
+                    println!("Error: {}", e);
+                }
+                last_error = e;
+                continue;
+            }
+        };
+        println!(
+            "{}: offset {} ms, delay {} ms, uncertainty {} ms",
+            sample.source,
+            sample.offset().num_milliseconds(),
+            sample.delay().num_milliseconds(),
+            sample.uncertainty.num_milliseconds()
+        );
+        if let Some(max_delay) = args.max_delay {
+            if sample.delay() > chrono::Duration::milliseconds(max_delay as i64) {
+                last_error = format!(
+                    "Discarding sample from {}, delay is above {} ms",
+                    sample.source, max_delay
+                )
+                .into();
+                println!("{}", last_error);
+                continue;
+            }
+        }
+        samples.push(sample);
+    }
+    if samples.is_empty() {
+        return Err(last_error);
+    }
+
+    let selection = selection::select(&samples)?;
+    for source in &selection.falsetickers {
+        println!("Ignoring falseticker {}", source);
+    }
+    if samples.len() > 1 {
+        println!(
+            "Combined offset of {} out of {} sources: {} ms",
+            samples.len() - selection.falsetickers.len(),
+            samples.len(),
+            selection.offset.num_milliseconds()
+        );
+    }
+    Ok(selection)
 }
 
-fn parse_datetime(naive: NaiveDateTime) -> DateTime<Utc> {
-    let naive_date = naive.date();
-    let naive_time = naive.time();
-    Utc.from_utc_datetime(&NaiveDateTime::new(naive_date, naive_time))
+// Refuses `offset` from `clock` unless it lies within the interval of every
+// Roughtime server that answers.
+fn check_roughtime(
+    args: &Args,
+    offset: chrono::Duration,
+    clock: &dyn SystemClock,
+) -> Result<(), SourceError> {
+    if args.roughtime.is_empty() {
+        return Ok(());
+    }
+
+    let mut verified = false;
+    let mut timed_out = false;
+    for server in &args.roughtime {
+        match roughtime::query_roughtime_server(server, args.read_timeout(), clock) {
+            Ok(sample) if sample.contains(offset) => verified = true,
+            Ok(sample) => {
+                return Err(format!(
+                    "Time is outside the Roughtime interval of {} ({} ± {} ms)",
+                    server.address,
+                    sample.midpoint,
+                    sample.radius.num_milliseconds()
+                )
+                .into())
+            }
+            Err(e) => {
+                println!("Skipping Roughtime server {}: {}", server.address, e);
+                timed_out = matches!(e, SourceError::Timeout(_));
+            }
+        }
+    }
+
+    let error = "No Roughtime server could confirm the time".to_string();
+    match (verified, timed_out) {
+        (true, _) => Ok(()),
+        (false, true) => Err(SourceError::Timeout(error)),
+        (false, false) => Err(SourceError::Failed(error)),
+    }
 }
 
-#[cfg(target_os = "windows")]
-fn sync_with_nist_server() -> Result<DateTime<Utc>, String> {
-    let time_string = get_nist_server_time().unwrap();
-    let time_tm = parse_nist_response(&time_string);
-    match set_system_time(time_tm) {
-        Ok(_) => Ok(time_tm),
-        Err(_e) => {
-            Err("Error setting system time, check your permissions.".into())
+/// Why a sync failed, which decides whether it is worth trying again.
+#[derive(Debug, Clone)]
+enum SyncError {
+    /// No trustworthy time could be obtained, usually because of the network
+    /// or the servers, which may well be fine by the next attempt.
+    Source(SourceError),
+    /// The clock couldn't be corrected, typically for lack of privileges.
+    Clock(String),
+    /// The options or the files they point to are unusable.
+    Config(String),
+}
+
+impl SyncError {
+    fn is_fatal(&self) -> bool {
+        !matches!(self, SyncError::Source(_))
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::Source(e) => write!(f, "{}", e),
+            SyncError::Clock(message) | SyncError::Config(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<SourceError> for SyncError {
+    fn from(e: SourceError) -> Self {
+        SyncError::Source(e)
+    }
+}
+
+// Everything that is kept from one sync to the next.
+struct SyncState {
+    sources: Vec<Box<dyn TimeSource>>,
+    clock: Box<dyn SystemClock>,
+    discipline: Option<Discipline>,
+    poll: Option<PollInterval>,
+}
+
+impl SyncState {
+    fn new(args: &Args) -> Result<Self, SyncError> {
+        if cfg!(target_os = "windows") && args.slew {
+            return Err(SyncError::Config(
+                "--slew is not supported on Windows".into(),
+            ));
+        }
+        let sources = build_sources(args).map_err(SyncError::Config)?;
+        let mut clock: Box<dyn SystemClock> = Box::new(OsClock);
+        let discipline = match args.discipline {
+            true => Some(
+                Discipline::new(clock.as_mut(), args.drift_file.clone())
+                    .map_err(SyncError::Config)?,
+            ),
+            false => None,
+        };
+        if args.min_poll > args.max_poll {
+            return Err(SyncError::Config(
+                "--min-poll can't be larger than --max-poll".into(),
+            ));
+        }
+        let poll = args
+            .adaptive
+            .then(|| PollInterval::new(args.min_poll, args.max_poll));
+        Ok(SyncState {
+            sources,
+            clock,
+            discipline,
+            poll,
+        })
+    }
+
+    // Time until the next sync after a successful one.
+    fn interval(&self, args: &Args) -> Duration {
+        match &self.poll {
+            Some(poll) => poll.interval(),
+            None => args.interval,
+        }
+    }
+}
+
+fn sync_with_nist_server(state: &mut SyncState, args: &Args) -> Result<DateTime<Utc>, SyncError> {
+    let selection = query_time(&mut state.sources, args, state.clock.as_ref())?;
+    let offset = selection.offset;
+    check_roughtime(args, offset, state.clock.as_ref())?;
+    let step_threshold = chrono::Duration::milliseconds(args.step_threshold as i64);
+    let action = clock::correct(
+        state.clock.as_mut(),
+        offset,
+        args.slew.then_some(step_threshold),
+    )
+    .map_err(|e| SyncError::Clock(format!("{}, check your permissions.", e)))?;
+    println!("Clock {}", action);
+
+    // Samples that can't tell the offset apart from zero, like daytime's
+    // whole seconds, don't mean the clock was knocked out.
+    let settled = offset.abs() <= step_threshold.max(selection.uncertainty);
+    if let Some(discipline) = &mut state.discipline {
+        match settled {
+            true => {
+                let frequency = discipline
+                    .update(state.clock.as_mut(), offset, selection.uncertainty)
+                    .map_err(SyncError::Clock)?;
+                println!("Clock frequency {:.3} ppm", frequency);
+            }
+            // Too far off to tell frequency error from whatever knocked the
+            // clock out, e.g. a suspend.
+            false => discipline.reset(),
+        }
+    }
+    if let Some(poll) = &mut state.poll {
+        let interval = poll.interval();
+        match settled {
+            true => poll.update(offset, selection.uncertainty),
+            false => poll.reset(),
+        }
+        if poll.interval() != interval {
+            println!("Poll interval now {} seconds", poll.interval().as_secs());
+        }
+    }
+    Ok(state.clock.now())
+}
+
+// Syncs and schedules the next sync, soon after a failure that may go away.
+// Only errors that retrying can't fix are returned.
+fn sync_and_schedule(
+    state: &mut SyncState,
+    args: &Args,
+    scheduler: &mut Scheduler,
+) -> Result<(), SyncError> {
+    match sync_with_nist_server(state, args) {
+        Ok(time) => {
+            println!(
+                "System time synced with NIST server: {}",
+                time.with_timezone(&Local)
+            );
+            scheduler.succeeded(Instant::now(), state.interval(args));
+            Ok(())
+        }
+        Err(e) if e.is_fatal() => Err(e),
+        Err(SyncError::Source(SourceError::Timeout(e))) => {
+            let delay = scheduler.failed(Instant::now());
+            println!(
+                "Error syncing system time: {}, the network may be down, retrying in {}",
+                e,
+                schedule::format_interval(delay)
+            );
+            Ok(())
+        }
+        Err(e) => {
+            let delay = scheduler.failed(Instant::now());
+            println!(
+                "Error syncing system time: {}, retrying in {}",
+                e,
+                schedule::format_interval(delay)
+            );
+            Ok(())
         }
     }
 }
@@ -215,7 +657,7 @@ fn uninstall_service() -> windows_service::Result<()> {
 
 #[cfg(target_os = "windows")]
 fn main_execution() -> windows_service::Result<()> {
-    use std::{ffi::OsString, sync::mpsc};
+    use std::ffi::OsString;
 
     use windows_service::{
         define_windows_service,
@@ -258,14 +700,7 @@ fn main_execution() -> windows_service::Result<()> {
         let args = Args::parse();
         let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)?;
 
-        println!(
-            "Syncing system time with NIST server every {} {}",
-            args.interval,
-            match args.interval {
-                1 => "minute",
-                _ => "minutes",
-            }
-        );
+        println!("Syncing system time with NIST server {}", schedule(&args));
 
         status_handle.set_service_status(ServiceStatus {
             service_type: SERVICE_TYPE,
@@ -277,32 +712,25 @@ fn main_execution() -> windows_service::Result<()> {
             process_id: None,
         })?;
 
-        let mut sleep_until = Utc::now();
+        let mut state = SyncState::new(&args);
+        let mut scheduler = Scheduler::new(Instant::now(), args.max_interval());
         loop {
-            match Utc::now() >= sleep_until {
-                true => {
-                    let time = sync_with_nist_server();
-                    match time {
-                        Ok(time) => {
-                            println!("System time set to {}", time);
-                            sleep_until =
-                                time + chrono::Duration::minutes((args.interval * 60) as i64);
-                        }
-                        Err(e) => {
-                            println!("Error: {}", e);
-                            break;
-                        }
-                    }
-                }
-                false => (),
-            }
-            match shutdown_rx.recv_timeout(Duration::from_secs(1)) {
+            // Wait for the next sync, or until the service is stopped.
+            match shutdown_rx.recv_timeout(scheduler.time_until_next(Instant::now())) {
                 // Break the loop either upon stop or channel disconnect
                 Ok(_) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
 
-                // Continue work if no events were received within the timeout
+                // The next sync is due
                 Err(mpsc::RecvTimeoutError::Timeout) => (),
             };
+            let result = match &mut state {
+                Ok(state) => sync_and_schedule(state, &args, &mut scheduler),
+                Err(e) => Err(e.clone()),
+            };
+            if let Err(e) = result {
+                println!("Error syncing system time: {}", e);
+                break;
+            }
         }
 
         status_handle.set_service_status(ServiceStatus {
@@ -415,68 +843,306 @@ fn main() -> windows_service::Result<()> {
 }
 
 #[cfg(not(target_os = "windows"))]
-fn sync_with_nist_server() -> Result<DateTime<Utc>, String> {
-    let time_string = get_nist_server_time().unwrap();
-    let time_tm = parse_nist_response(&time_string);
-    let local: DateTime<Local> = Local.from_utc_datetime(&time_tm.naive_utc());
-    match set_system_time(local) {
-        Ok(_) => Ok(time_tm),
-        Err(_e) => {
-            return Err("Error setting system time, check your permissions.".into());
+fn main() {
+    let args = Args::parse();
+    println!("Syncing system time with NIST server {}", schedule(&args));
+    let mut state = match SyncState::new(&args) {
+        Ok(state) => state,
+        Err(e) => {
+            println!("Error syncing system time: {}", e);
+            return;
+        }
+    };
+    let mut scheduler = Scheduler::new(Instant::now(), args.max_interval());
+    let (wake_tx, wake_rx) = mpsc::channel();
+    #[cfg(target_os = "linux")]
+    {
+        network::wait_until_ready();
+        network::watch(wake_tx.clone());
+        suspend::watch(wake_tx.clone());
+    }
+    loop {
+        // Wait for the next sync, or for something that calls for one sooner.
+        if wake_rx
+            .recv_timeout(scheduler.time_until_next(Instant::now()))
+            .is_ok()
+        {
+            while wake_rx.try_recv().is_ok() {}
+        }
+        if let Err(e) = sync_and_schedule(&mut state, &args, &mut scheduler) {
+            println!("Error syncing system time: {}", e);
+            break;
         }
     }
 }
 
-#[cfg(not(target_os = "windows"))]
-fn set_system_time(datetime: DateTime<Local>) -> Result<i32, String> {
-    use libc::{settimeofday, time_t, timeval};
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use clock::SimulatedClock;
+    use source::{ms, test_sample, MockSource};
+    use std::{cell::RefCell, rc::Rc};
 
-    let timestamp = datetime.timestamp();
-    let tv = timeval {
-        tv_sec: timestamp as time_t,
-        tv_usec: 0,
-    };
-    let tz = std::ptr::null();
+    fn args(extra: &[&str]) -> Args {
+        Args::parse_from(["nist_time_sync"].iter().chain(extra))
+    }
 
-    let result = unsafe { settimeofday(&tv as *const timeval, tz) };
+    fn mock(replies: Vec<Result<source::Sample, SourceError>>) -> Box<dyn TimeSource> {
+        Box::new(MockSource::new(replies))
+    }
 
-    match result {
-        0 => Ok(result),
-        _ => Err("Error setting system time".into()),
+    #[test]
+    fn queries_every_source() {
+        let now = Utc::now();
+        let mut sources = vec![
+            mock(vec![Err("unreachable".into())]),
+            mock(vec![Ok(test_sample("a", now, ms(1000), ms(10)))]),
+            mock(vec![Ok(test_sample("b", now, ms(1004), ms(10)))]),
+        ];
+        let selection = query_time(&mut sources, &args(&[]), &OsClock).unwrap();
+        assert_eq!(selection.offset, ms(1002));
     }
-}
 
-#[cfg(not(target_os = "windows"))]
-fn main() {
-    let args = Args::parse();
-    match args.interval {
-        1.. => {
-            println!(
-                "Syncing system time with NIST server every {} {}",
-                args.interval,
-                match args.interval {
-                    1 => "minute",
-                    _ => "minutes",
-                }
-            );
-            loop {
-                let time = sync_with_nist_server();
-                match time {
-                    Ok(time) => {
-                        let local: DateTime<Local> = Local.from_utc_datetime(&time.naive_utc());
-                        println!("System time synced with NIST server: {}", local);
-                        thread::sleep(Duration::from_secs(args.interval * 60));
-                    }
-                    Err(e) => {
-                        println!("Error syncing system time: {}", e);
-                        break;
-                    }
-                }
-            }
+    #[test]
+    fn reports_the_last_error_without_samples() {
+        let mut sources = vec![
+            mock(vec![Err("unreachable".into())]),
+            mock(vec![Err(SourceError::Timeout("timed out".into()))]),
+        ];
+        assert_eq!(
+            query_time(&mut sources, &args(&[]), &OsClock).err(),
+            Some(SourceError::Timeout("timed out".into()))
+        );
+    }
+
+    #[test]
+    fn discards_samples_above_max_delay() {
+        let mut sample = test_sample("slow", Utc::now(), ms(1000), ms(10));
+        sample.received = sample.sent + Duration::from_millis(300);
+        let mut sources = vec![mock(vec![Ok(sample)])];
+        let args = args(&["--max-delay", "200"]);
+        assert!(query_time(&mut sources, &args, &OsClock).is_err());
+    }
+
+    #[test]
+    fn bounds_the_max_delay() {
+        assert_eq!(
+            args(&["--max-delay", "86400000"]).max_delay,
+            Some(86_400_000)
+        );
+        for value in ["86400001", "9223372036854775808", "18446744073709551615"] {
+            let parsed = Args::try_parse_from(["nist_time_sync", "--max-delay", value]);
+            assert!(parsed.is_err(), "{}", value);
         }
-        _ => {
-            println!("Interval must be higher than 0");
-            return;
+    }
+
+    // A source that knows the true time of `clock`.
+    fn truth(clock: &SimulatedClock) -> Box<dyn TimeSource> {
+        let offset = clock.true_time() - clock.now();
+        mock(vec![Ok(test_sample("truth", clock.now(), offset, ms(10)))])
+    }
+
+    fn state(sources: Vec<Box<dyn TimeSource>>, clock: SimulatedClock) -> SyncState {
+        SyncState {
+            sources,
+            clock: Box::new(clock),
+            discipline: None,
+            poll: None,
+        }
+    }
+
+    fn start() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 10, 15, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn steps_the_clock_to_the_true_time() {
+        let clock = SimulatedClock::new(start(), ms(-2500), 0.0);
+        let mut state = state(vec![truth(&clock)], clock);
+        let time = sync_with_nist_server(&mut state, &args(&[])).unwrap();
+        assert_eq!(time, start());
+        assert_eq!(state.clock.now(), start());
+    }
+
+    #[test]
+    fn slews_offsets_below_the_step_threshold() {
+        let clock = SimulatedClock::new(start(), ms(-100), 0.0);
+        let mut state = state(vec![truth(&clock)], clock);
+        // The slew only takes effect as time passes, which it doesn't here.
+        let time = sync_with_nist_server(&mut state, &args(&["--slew"])).unwrap();
+        assert_eq!(time, start() - ms(100));
+    }
+
+    #[test]
+    fn steps_offsets_above_the_step_threshold_in_slew_mode() {
+        let clock = SimulatedClock::new(start(), ms(-200), 0.0);
+        let mut state = state(vec![truth(&clock)], clock);
+        let time = sync_with_nist_server(&mut state, &args(&["--slew"])).unwrap();
+        assert_eq!(time, start());
+    }
+
+    #[test]
+    fn bounds_the_step_threshold() {
+        assert_eq!(
+            args(&["--step-threshold", "86400000"]).step_threshold,
+            86_400_000
+        );
+        for value in ["86400001", "9223372036854775808", "18446744073709551615"] {
+            let parsed = Args::try_parse_from(["nist_time_sync", "--step-threshold", value]);
+            assert!(parsed.is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn ignores_lying_sources() {
+        let clock = SimulatedClock::new(start(), ms(-2500), 0.0);
+        let liar = |offset| mock(vec![Ok(test_sample("liar", clock.now(), offset, ms(10)))]);
+        let sources = vec![
+            truth(&clock),
+            liar(ms(60_000)),
+            truth(&clock),
+            liar(ms(60_000)),
+            truth(&clock),
+        ];
+        let mut state = state(sources, clock);
+        let time = sync_with_nist_server(&mut state, &args(&[])).unwrap();
+        assert_eq!(time, start());
+    }
+
+    #[test]
+    fn leaves_the_clock_alone_when_sources_disagree() {
+        let clock = SimulatedClock::new(start(), ms(-2500), 0.0);
+        let liar = test_sample("liar", clock.now(), ms(60_000), ms(10));
+        let liar = mock(vec![Ok(liar)]);
+        let mut state = state(vec![truth(&clock), liar], clock);
+        let result = sync_with_nist_server(&mut state, &args(&[]));
+        assert!(matches!(result, Err(SyncError::Source(_))));
+        assert_eq!(state.clock.now(), start() - ms(2500));
+    }
+
+    #[test]
+    fn leaves_the_clock_alone_without_a_sample() {
+        let clock = SimulatedClock::new(start(), ms(-2500), 0.0);
+        let mut state = state(vec![mock(vec![Err("unreachable".into())])], clock);
+        let result = sync_with_nist_server(&mut state, &args(&[]));
+        assert!(matches!(result, Err(SyncError::Source(_))));
+        assert_eq!(state.clock.now(), start() - ms(2500));
+    }
+
+    // A simulated clock shared with the sync state, so tests can let time
+    // pass between syncs.
+    #[derive(Clone)]
+    struct SharedClock(Rc<RefCell<SimulatedClock>>);
+
+    impl SystemClock for SharedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0.borrow().now()
+        }
+
+        fn step(&mut self, offset: chrono::Duration) -> Result<(), String> {
+            self.0.borrow_mut().step(offset)
+        }
+
+        fn slew(&mut self, offset: chrono::Duration) -> Result<(), String> {
+            self.0.borrow_mut().slew(offset)
+        }
+
+        fn frequency(&self) -> Result<f64, String> {
+            self.0.borrow().frequency()
+        }
+
+        fn set_frequency(&mut self, ppm: f64) -> Result<(), String> {
+            self.0.borrow_mut().set_frequency(ppm)
+        }
+    }
+
+    // Syncs `syncs` times, 1024 seconds apart, with a clock drifting
+    // `drift_ppm` and samples of the true time off by `noise` milliseconds
+    // (cycled through) within `uncertainty`, and returns the frequency the
+    // discipline ended up with.
+    fn disciplined_frequency(
+        drift_ppm: f64,
+        syncs: usize,
+        noise: &[i64],
+        uncertainty: chrono::Duration,
+    ) -> f64 {
+        let mut clock = SimulatedClock::new(start(), chrono::Duration::zero(), drift_ppm);
+        let discipline = Discipline::new(&mut clock, None).unwrap();
+        let clock = SharedClock(Rc::new(RefCell::new(clock)));
+        let mut state = SyncState {
+            sources: Vec::new(),
+            clock: Box::new(clock.clone()),
+            discipline: Some(discipline),
+            poll: None,
+        };
+        for sync in 0..syncs {
+            let sample = {
+                let mut clock = clock.0.borrow_mut();
+                clock.advance(chrono::Duration::seconds(1024));
+                let offset = clock.true_time() - clock.now() + ms(noise[sync % noise.len()]);
+                test_sample("daytime", clock.now(), offset, uncertainty)
+            };
+            state.sources = vec![mock(vec![Ok(sample)])];
+            sync_with_nist_server(&mut state, &args(&[])).unwrap();
         }
+        state.clock.frequency().unwrap()
+    }
+
+    #[test]
+    fn disciplines_the_frequency() {
+        let frequency = disciplined_frequency(50.0, 100, &[5, -5], ms(10));
+        assert!((frequency + 50.0).abs() < 1.0, "{}", frequency);
+    }
+
+    #[test]
+    fn disciplines_with_offsets_within_the_sample_uncertainty() {
+        // Daytime only has whole seconds, so its offsets are often above the
+        // step threshold without the clock having been knocked out.
+        let frequency = disciplined_frequency(50.0, 200, &[200, -200], ms(500));
+        // Whole seconds take days to pin the drift down, but never push the
+        // frequency past it.
+        assert!((-55.0..-35.0).contains(&frequency), "{}", frequency);
+    }
+
+    #[test]
+    fn doesnt_mistake_whole_second_noise_for_drift() {
+        let frequency = disciplined_frequency(0.0, 100, &[200, -200, 0], ms(500));
+        assert_eq!(frequency, 0.0);
+    }
+
+    #[test]
+    fn restarts_the_discipline_after_a_jump() {
+        assert_eq!(disciplined_frequency(0.0, 2, &[600], ms(10)), 0.0);
+    }
+
+    // Syncs `count` times with samples `offset` and `uncertainty` away from
+    // the clock and returns the resulting adaptive interval.
+    fn adaptive_interval(
+        count: usize,
+        offset: chrono::Duration,
+        uncertainty: chrono::Duration,
+    ) -> Duration {
+        let clock = SimulatedClock::new(start(), chrono::Duration::zero(), 0.0);
+        let mut state = state(Vec::new(), clock);
+        state.poll = Some(PollInterval::new(6, 10));
+        for _ in 0..count {
+            let sample = test_sample("daytime", state.clock.now(), offset, uncertainty);
+            state.sources = vec![mock(vec![Ok(sample)])];
+            sync_with_nist_server(&mut state, &args(&[])).unwrap();
+        }
+        state.interval(&args(&[]))
+    }
+
+    #[test]
+    fn lengthens_the_interval_with_offsets_within_the_sample_uncertainty() {
+        let interval = adaptive_interval(6, ms(600), ms(1000));
+        assert_eq!(interval, poll::interval(7));
+    }
+
+    #[test]
+    fn goes_back_to_the_shortest_interval_after_a_jump() {
+        let interval = adaptive_interval(6, ms(600), ms(10));
+        assert_eq!(interval, poll::interval(6));
     }
 }
@@ -0,0 +1,74 @@
+use crate::source::SourceError;
+use std::{
+    io,
+    net::{SocketAddr, TcpStream, UdpSocket},
+    time::Duration,
+};
+
+/// How long to wait for a connection and for each read or write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub read: Duration,
+}
+
+/// Connects to `addr` and applies the read timeout to both directions.
+pub fn tcp_connect(addr: SocketAddr, timeouts: Timeouts) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&addr, timeouts.connect)?;
+    stream.set_read_timeout(Some(timeouts.read))?;
+    stream.set_write_timeout(Some(timeouts.read))?;
+    Ok(stream)
+}
+
+/// Tells timeouts apart from other network errors. A socket timeout shows up
+/// as `WouldBlock` on Unix and `TimedOut` on Windows.
+pub fn io_error(error: io::Error) -> SourceError {
+    match error.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            SourceError::Timeout("Timed out waiting for the server".into())
+        }
+        _ => SourceError::Failed(error.to_string()),
+    }
+}
+
+/// Opens a UDP socket connected to `addr`, so only replies from that peer are
+/// received.
+pub fn udp_socket(addr: SocketAddr, timeout: Duration) -> Result<UdpSocket, String> {
+    let bind_addr = match addr.is_ipv4() {
+        true => "0.0.0.0:0",
+        false => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind_addr).map_err(|e| e.to_string())?;
+    socket.connect(addr).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    Ok(socket)
+}
+
+/// Splits `host[:port]` or `[v6 address][:port]`.
+pub fn split_host_port(server: &str, default_port: u16) -> Result<(String, u16), String> {
+    let (host, port) = match server.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => (host, Some(port)),
+        _ => (server, None),
+    };
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid port in {}", server))?,
+        None => default_port,
+    };
+    Ok((
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+        port,
+    ))
+}
+
+pub fn join_host_port(host: &str, port: u16) -> String {
+    match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    }
+}
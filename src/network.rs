@@ -0,0 +1,564 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::mpsc::Sender,
+    thread,
+    time::{Duration, Instant},
+};
+
+// Longest the first sync waits for the network before trying anyway.
+const READY_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+// Bringing up a connection takes a burst of link, address and route changes,
+// the sync waits until nothing changed for this long.
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+
+const GROUPS: libc::c_int = libc::RTMGRP_LINK
+    | libc::RTMGRP_IPV4_IFADDR
+    | libc::RTMGRP_IPV4_ROUTE
+    | libc::RTMGRP_IPV6_IFADDR
+    | libc::RTMGRP_IPV6_ROUTE;
+
+/// Blocks until the machine has a way to reach a server, e.g. while DHCP is
+/// still running at boot, giving up after a couple of minutes.
+pub fn wait_until_ready() {
+    match wait_for_network(READY_TIMEOUT) {
+        Ok(true) => (),
+        Ok(false) => println!("The network is still down, trying anyway"),
+        Err(e) => println!("Can't tell whether the network is up: {}", e),
+    }
+}
+
+/// Sends on `wake` once the network settles after the default route, an
+/// address or an interface changed, as the time may now come from elsewhere
+/// or only just be reachable.
+pub fn watch(wake: Sender<()>) {
+    thread::spawn(move || {
+        if let Err(e) = watch_changes(&wake) {
+            println!("Stopped watching the network: {}", e);
+        }
+    });
+}
+
+fn wait_for_network(timeout: Duration) -> io::Result<bool> {
+    // Subscribe before looking, so a change in between isn't missed.
+    let events = Netlink::open(GROUPS as u32)?;
+    let deadline = Instant::now() + timeout;
+    let mut waiting = false;
+    while !is_ready()? {
+        if !waiting {
+            println!("Waiting for the network to come up");
+            waiting = true;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || !events.wait(Some(remaining))? {
+            return Ok(false);
+        }
+        receive_or_overflow(&events)?;
+    }
+    Ok(true)
+}
+
+fn watch_changes(wake: &Sender<()>) -> io::Result<()> {
+    let events = Netlink::open(GROUPS as u32)?;
+    let mut state = NetworkState::default();
+    let netlink = Netlink::open(0)?;
+    for kind in [libc::RTM_GETLINK, libc::RTM_GETADDR, libc::RTM_GETROUTE] {
+        for message in netlink.dump(kind)? {
+            state.update(&message);
+        }
+    }
+
+    loop {
+        let changed = match receive_or_overflow(&events)? {
+            // Every message has to go through `state`, even after a change.
+            Some(messages) => {
+                let mut changed = false;
+                for message in &messages {
+                    changed |= state.update(message);
+                }
+                changed
+            }
+            None => true,
+        };
+        if !changed {
+            continue;
+        }
+
+        while events.wait(Some(SETTLE_TIME))? {
+            for message in receive_or_overflow(&events)?.unwrap_or_default() {
+                state.update(&message);
+            }
+        }
+        if is_ready()? {
+            println!("Network changed, syncing now");
+            let _ = wake.send(());
+        }
+    }
+}
+
+// None when the kernel had to drop notifications because we were too slow,
+// which means something changed.
+fn receive_or_overflow(events: &Netlink) -> io::Result<Option<Vec<Message>>> {
+    match events.receive() {
+        Ok(messages) => Ok(Some(messages)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// A default route, or failing that an address that isn't loopback or link
+// local, which is enough to reach a server on the local network.
+fn is_ready() -> io::Result<bool> {
+    let netlink = Netlink::open(0)?;
+    if netlink
+        .dump(libc::RTM_GETROUTE)?
+        .iter()
+        .any(Message::is_default_route)
+    {
+        return Ok(true);
+    }
+    Ok(netlink
+        .dump(libc::RTM_GETADDR)?
+        .iter()
+        .any(Message::is_global_address))
+}
+
+// What the last messages said about the interfaces, global addresses and
+// default routes, to tell real changes from the statistics, flags and
+// lifetime refreshes the kernel also announces.
+#[derive(Default)]
+struct NetworkState {
+    // Whether each interface was running.
+    links: HashMap<i32, bool>,
+    addresses: HashSet<Vec<u8>>,
+    routes: HashSet<Vec<u8>>,
+}
+
+impl NetworkState {
+    // Records what `message` says and returns true if that changed anything.
+    fn update(&mut self, message: &Message) -> bool {
+        match message.kind {
+            libc::RTM_NEWLINK | libc::RTM_DELLINK => message.link_changed(&mut self.links),
+            libc::RTM_NEWADDR | libc::RTM_DELADDR if message.is_global_address() => {
+                let key = message.address_key();
+                match message.kind == libc::RTM_NEWADDR {
+                    true => self.addresses.insert(key),
+                    false => self.addresses.remove(&key),
+                }
+            }
+            libc::RTM_NEWROUTE | libc::RTM_DELROUTE if message.is_default_route() => {
+                let key = message.route_key();
+                match message.kind == libc::RTM_NEWROUTE {
+                    true => self.routes.insert(key),
+                    false => self.routes.remove(&key),
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+struct Message {
+    kind: u16,
+    payload: Vec<u8>,
+}
+
+impl Message {
+    // rtmsg: family, dst_len, src_len, tos, table, protocol, scope, type.
+    fn is_default_route(&self) -> bool {
+        matches!(self.kind, libc::RTM_NEWROUTE | libc::RTM_DELROUTE)
+            && self.payload.len() >= 12
+            && self.payload[1] == 0
+            && self.payload[4] == libc::RT_TABLE_MAIN
+            && self.payload[7] == libc::RTN_UNICAST
+    }
+
+    // ifaddrmsg: family, prefixlen, flags, scope, index.
+    fn is_global_address(&self) -> bool {
+        matches!(self.kind, libc::RTM_NEWADDR | libc::RTM_DELADDR)
+            && self.payload.len() >= 8
+            && self.payload[3] == libc::RT_SCOPE_UNIVERSE
+    }
+
+    // ifinfomsg: family, pad, type, index, flags, change. Records whether the
+    // interface is running and returns true if that changed.
+    fn link_changed(&self, links: &mut HashMap<i32, bool>) -> bool {
+        if !matches!(self.kind, libc::RTM_NEWLINK | libc::RTM_DELLINK) || self.payload.len() < 16 {
+            return false;
+        }
+        let index = i32::from_ne_bytes(self.payload[4..8].try_into().unwrap());
+        let flags = u32::from_ne_bytes(self.payload[8..12].try_into().unwrap());
+        if flags & libc::IFF_LOOPBACK as u32 != 0 {
+            return false;
+        }
+        let running = self.kind == libc::RTM_NEWLINK && flags & libc::IFF_RUNNING as u32 != 0;
+        links.insert(index, running) != Some(running)
+    }
+
+    // Identifies an address by family, prefix length, interface and the
+    // address itself, leaving out the flags and lifetimes a refresh changes.
+    fn address_key(&self) -> Vec<u8> {
+        let mut key = [&self.payload[..2], &self.payload[4..8]].concat();
+        self.push_attributes(&mut key, 8, &[libc::IFA_ADDRESS, libc::IFA_LOCAL]);
+        key
+    }
+
+    // Identifies a default route by family, gateway, interface and metric.
+    fn route_key(&self) -> Vec<u8> {
+        let mut key = vec![self.payload[0]];
+        let attributes = [libc::RTA_GATEWAY, libc::RTA_OIF, libc::RTA_PRIORITY];
+        self.push_attributes(&mut key, 12, &attributes);
+        key
+    }
+
+    fn push_attributes(&self, key: &mut Vec<u8>, header_len: usize, kinds: &[u16]) {
+        for (kind, value) in self.attributes(header_len) {
+            if kinds.contains(&kind) {
+                key.extend_from_slice(&kind.to_ne_bytes());
+                key.extend_from_slice(value);
+            }
+        }
+    }
+
+    // The rtattr list after the fixed header: length, type and value, each
+    // padded to 4 bytes.
+    fn attributes(&self, header_len: usize) -> Vec<(u16, &[u8])> {
+        let mut attributes = Vec::new();
+        let mut pos = header_len;
+        while let Some(header) = self.payload.get(pos..pos + 4) {
+            let length = u16::from_ne_bytes([header[0], header[1]]) as usize;
+            let kind = u16::from_ne_bytes([header[2], header[3]]);
+            let Some(value) = self.payload.get(pos + 4..pos + length) else {
+                break;
+            };
+            attributes.push((kind, value));
+            pos += length.next_multiple_of(4);
+        }
+        attributes
+    }
+}
+
+// A rtnetlink socket, see rtnetlink(7).
+struct Netlink {
+    fd: OwnedFd,
+}
+
+impl Netlink {
+    // Subscribed to the multicast `groups`, or to nothing for plain requests.
+    fn open(groups: u32) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = groups;
+        let result = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        match result {
+            0 => Ok(Netlink { fd }),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    // Asks for every object of a kind, e.g. all routes.
+    fn dump(&self, kind: u16) -> io::Result<Vec<Message>> {
+        // nlmsghdr followed by rtgenmsg, just an address family padded to 4.
+        let mut request = Vec::with_capacity(20);
+        request.extend_from_slice(&20u32.to_ne_bytes());
+        request.extend_from_slice(&kind.to_ne_bytes());
+        request.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+        request.extend_from_slice(&1u32.to_ne_bytes());
+        request.extend_from_slice(&0u32.to_ne_bytes());
+        request.extend_from_slice(&[libc::AF_UNSPEC as u8, 0, 0, 0]);
+        let sent = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                request.as_ptr() as *const libc::c_void,
+                request.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut messages = Vec::new();
+        loop {
+            for message in self.receive()? {
+                match message.kind as libc::c_int {
+                    libc::NLMSG_DONE => return Ok(messages),
+                    libc::NLMSG_ERROR => return Err(io::Error::other("Netlink request failed")),
+                    _ => messages.push(message),
+                }
+            }
+        }
+    }
+
+    // Whether a message arrives within `timeout`, or at all when there is
+    // none.
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut poll = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int
+        });
+        match unsafe { libc::poll(&mut poll, 1, timeout) } {
+            -1 => Err(io::Error::last_os_error()),
+            ready => Ok(ready > 0),
+        }
+    }
+
+    fn receive(&self) -> io::Result<Vec<Message>> {
+        let mut buffer = vec![0u8; 64 * 1024];
+        let length = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if length < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(parse_messages(&buffer[..length as usize]))
+    }
+}
+
+// Splits what a netlink socket returned into messages, each a nlmsghdr of
+// length, type, flags, sequence and port followed by the payload.
+fn parse_messages(buffer: &[u8]) -> Vec<Message> {
+    let mut messages = Vec::new();
+    let mut pos = 0;
+    while pos + 16 <= buffer.len() {
+        let size = u32::from_ne_bytes(buffer[pos..pos + 4].try_into().unwrap()) as usize;
+        if size < 16 || pos + size > buffer.len() {
+            break;
+        }
+        messages.push(Message {
+            kind: u16::from_ne_bytes(buffer[pos + 4..pos + 6].try_into().unwrap()),
+            payload: buffer[pos + 16..pos + size].to_vec(),
+        });
+        pos += size.next_multiple_of(4);
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A nlmsghdr around `payload`, padded to 4 bytes like the kernel does.
+    fn netlink_message(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&(16 + payload.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(&[0; 10]);
+        message.extend_from_slice(payload);
+        message.resize(message.len().next_multiple_of(4), 0);
+        message
+    }
+
+    fn attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut attribute = Vec::new();
+        attribute.extend_from_slice(&(4 + value.len() as u16).to_ne_bytes());
+        attribute.extend_from_slice(&kind.to_ne_bytes());
+        attribute.extend_from_slice(value);
+        attribute.resize(attribute.len().next_multiple_of(4), 0);
+        attribute
+    }
+
+    fn parse(kind: u16, payload: &[u8]) -> Message {
+        let mut messages = parse_messages(&netlink_message(kind, payload));
+        assert_eq!(messages.len(), 1);
+        messages.remove(0)
+    }
+
+    fn link(kind: u16, index: i32, flags: libc::c_int) -> Message {
+        let mut payload = vec![libc::AF_UNSPEC as u8, 0, 1, 0];
+        payload.extend_from_slice(&index.to_ne_bytes());
+        payload.extend_from_slice(&(flags as u32).to_ne_bytes());
+        payload.extend_from_slice(&0u32.to_ne_bytes());
+        parse(kind, &payload)
+    }
+
+    fn address(kind: u16, scope: u8, address: [u8; 4], flags: u8) -> Message {
+        let mut payload = vec![libc::AF_INET as u8, 24, flags, scope];
+        payload.extend_from_slice(&2i32.to_ne_bytes());
+        payload.extend(attribute(libc::IFA_LOCAL, &address));
+        payload.extend(attribute(libc::IFA_ADDRESS, &address));
+        parse(kind, &payload)
+    }
+
+    fn route(kind: u16, dst_len: u8, table: u8, route_type: u8, gateway: [u8; 4]) -> Message {
+        let mut payload = vec![libc::AF_INET as u8, dst_len, 0, 0, table, 4, 0, route_type];
+        payload.extend_from_slice(&0u32.to_ne_bytes());
+        payload.extend(attribute(libc::RTA_GATEWAY, &gateway));
+        payload.extend(attribute(libc::RTA_OIF, &2i32.to_ne_bytes()));
+        parse(kind, &payload)
+    }
+
+    fn default_route(kind: u16, gateway: [u8; 4]) -> Message {
+        route(kind, 0, libc::RT_TABLE_MAIN, libc::RTN_UNICAST, gateway)
+    }
+
+    const RUNNING: libc::c_int = libc::IFF_UP | libc::IFF_RUNNING;
+
+    #[test]
+    fn splits_netlink_messages() {
+        let mut buffer = netlink_message(libc::RTM_NEWLINK, &[1, 2, 3]);
+        buffer.extend(netlink_message(libc::RTM_NEWADDR, &[4; 8]));
+        let messages = parse_messages(&buffer);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].kind, libc::RTM_NEWLINK);
+        // The padding isn't part of the payload.
+        assert_eq!(messages[0].payload, [1, 2, 3]);
+        assert_eq!(messages[1].kind, libc::RTM_NEWADDR);
+        assert_eq!(messages[1].payload, [4; 8]);
+    }
+
+    #[test]
+    fn stops_at_a_truncated_or_garbled_message() {
+        let mut buffer = netlink_message(libc::RTM_NEWLINK, &[0; 16]);
+        let second = netlink_message(libc::RTM_NEWLINK, &[0; 16]);
+        buffer.extend_from_slice(&second[..20]);
+        assert_eq!(parse_messages(&buffer).len(), 1);
+
+        let mut garbled = netlink_message(libc::RTM_NEWLINK, &[0; 16]);
+        garbled[..4].copy_from_slice(&8u32.to_ne_bytes());
+        assert!(parse_messages(&garbled).is_empty());
+        assert!(parse_messages(&[0; 10]).is_empty());
+    }
+
+    #[test]
+    fn recognises_default_routes() {
+        let gateway = [192, 168, 1, 1];
+        assert!(default_route(libc::RTM_NEWROUTE, gateway).is_default_route());
+        assert!(default_route(libc::RTM_DELROUTE, gateway).is_default_route());
+        let subnet = route(
+            libc::RTM_NEWROUTE,
+            24,
+            libc::RT_TABLE_MAIN,
+            libc::RTN_UNICAST,
+            gateway,
+        );
+        assert!(!subnet.is_default_route());
+        let local = route(
+            libc::RTM_NEWROUTE,
+            0,
+            libc::RT_TABLE_LOCAL,
+            libc::RTN_UNICAST,
+            gateway,
+        );
+        assert!(!local.is_default_route());
+        let blackhole = route(
+            libc::RTM_NEWROUTE,
+            0,
+            libc::RT_TABLE_MAIN,
+            libc::RTN_BLACKHOLE,
+            gateway,
+        );
+        assert!(!blackhole.is_default_route());
+        assert!(!parse(libc::RTM_NEWROUTE, &[0; 8]).is_default_route());
+        assert!(!parse(libc::RTM_NEWADDR, &[0; 12]).is_default_route());
+    }
+
+    #[test]
+    fn recognises_global_addresses() {
+        let ip = [192, 168, 1, 10];
+        assert!(address(libc::RTM_NEWADDR, libc::RT_SCOPE_UNIVERSE, ip, 0).is_global_address());
+        assert!(!address(libc::RTM_NEWADDR, libc::RT_SCOPE_LINK, ip, 0).is_global_address());
+        assert!(!address(libc::RTM_NEWADDR, libc::RT_SCOPE_HOST, ip, 0).is_global_address());
+        assert!(!parse(libc::RTM_NEWADDR, &[0; 4]).is_global_address());
+        assert!(!parse(libc::RTM_NEWROUTE, &[0; 8]).is_global_address());
+    }
+
+    #[test]
+    fn notices_links_starting_and_stopping() {
+        let mut links = HashMap::new();
+        assert!(link(libc::RTM_NEWLINK, 2, RUNNING).link_changed(&mut links));
+        // Statistics and unrelated flags come in the same message.
+        assert!(!link(libc::RTM_NEWLINK, 2, RUNNING | libc::IFF_PROMISC).link_changed(&mut links));
+        assert!(link(libc::RTM_NEWLINK, 2, libc::IFF_UP).link_changed(&mut links));
+        assert!(link(libc::RTM_NEWLINK, 3, RUNNING).link_changed(&mut links));
+        assert!(link(libc::RTM_DELLINK, 3, RUNNING).link_changed(&mut links));
+        assert!(!link(libc::RTM_NEWLINK, 1, RUNNING | libc::IFF_LOOPBACK).link_changed(&mut links));
+        assert!(!parse(libc::RTM_NEWLINK, &[0; 12]).link_changed(&mut links));
+    }
+
+    #[test]
+    fn ignores_refreshed_addresses() {
+        let mut state = NetworkState::default();
+        let universe = libc::RT_SCOPE_UNIVERSE;
+        assert!(state.update(&address(libc::RTM_NEWADDR, universe, [10, 0, 0, 5], 0)));
+        // Lifetime and flag updates of the same address.
+        assert!(!state.update(&address(libc::RTM_NEWADDR, universe, [10, 0, 0, 5], 0)));
+        assert!(!state.update(&address(libc::RTM_NEWADDR, universe, [10, 0, 0, 5], 0x80)));
+        assert!(state.update(&address(libc::RTM_NEWADDR, universe, [10, 0, 0, 6], 0)));
+        assert!(state.update(&address(libc::RTM_DELADDR, universe, [10, 0, 0, 5], 0)));
+        assert!(!state.update(&address(libc::RTM_DELADDR, universe, [10, 0, 0, 5], 0)));
+        // Link-local addresses don't get us to a server.
+        assert!(!state.update(&address(
+            libc::RTM_NEWADDR,
+            libc::RT_SCOPE_LINK,
+            [169, 254, 0, 1],
+            0
+        )));
+    }
+
+    #[test]
+    fn ignores_refreshed_default_routes() {
+        let mut state = NetworkState::default();
+        assert!(state.update(&default_route(libc::RTM_NEWROUTE, [10, 0, 0, 1])));
+        assert!(!state.update(&default_route(libc::RTM_NEWROUTE, [10, 0, 0, 1])));
+        assert!(state.update(&default_route(libc::RTM_NEWROUTE, [10, 0, 0, 254])));
+        assert!(state.update(&default_route(libc::RTM_DELROUTE, [10, 0, 0, 1])));
+        assert!(!state.update(&default_route(libc::RTM_DELROUTE, [10, 0, 0, 1])));
+        let subnet = route(
+            libc::RTM_NEWROUTE,
+            24,
+            libc::RT_TABLE_MAIN,
+            libc::RTN_UNICAST,
+            [0; 4],
+        );
+        assert!(!state.update(&subnet));
+    }
+
+    #[test]
+    fn reads_route_attributes() {
+        let message = default_route(libc::RTM_NEWROUTE, [10, 0, 0, 1]);
+        let attributes = message.attributes(12);
+        assert_eq!(
+            attributes,
+            [
+                (libc::RTA_GATEWAY, &[10, 0, 0, 1][..]),
+                (libc::RTA_OIF, &2i32.to_ne_bytes()[..])
+            ]
+        );
+        // An attribute claiming more than is there ends the list.
+        let mut payload = message.payload.clone();
+        payload[12..14].copy_from_slice(&200u16.to_ne_bytes());
+        assert!(parse(libc::RTM_NEWROUTE, &payload)
+            .attributes(12)
+            .is_empty());
+    }
+}
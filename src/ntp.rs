@@ -0,0 +1,461 @@
+use crate::{
+    clock::SystemClock,
+    net,
+    source::{Sample, SourceError, TimeSource},
+};
+use chrono::{DateTime, Utc};
+use std::{
+    net::ToSocketAddrs,
+    time::{Duration, Instant},
+};
+
+pub const NTP_PACKET_LEN: usize = 48;
+// Seconds between the NTP epoch (1900-01-01) and the Unix epoch.
+pub const NTP_UNIX_OFFSET: i64 = 2_208_988_800;
+const NTP_VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+
+/// The fixed 48-byte NTPv4 header (RFC 5905, section 7.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NtpPacket {
+    pub leap: u8,
+    pub version: u8,
+    pub mode: u8,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    pub reference_id: [u8; 4],
+    pub reference_timestamp: u64,
+    pub origin_timestamp: u64,
+    pub receive_timestamp: u64,
+    pub transmit_timestamp: u64,
+}
+
+impl NtpPacket {
+    pub fn client_request(transmit: DateTime<Utc>) -> Self {
+        NtpPacket {
+            version: NTP_VERSION,
+            mode: MODE_CLIENT,
+            transmit_timestamp: to_ntp_timestamp(transmit),
+            ..Default::default()
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; NTP_PACKET_LEN] {
+        let mut buffer = [0u8; NTP_PACKET_LEN];
+        buffer[0] = (self.leap << 6) | ((self.version & 0x07) << 3) | (self.mode & 0x07);
+        buffer[1] = self.stratum;
+        buffer[2] = self.poll as u8;
+        buffer[3] = self.precision as u8;
+        buffer[4..8].copy_from_slice(&self.root_delay.to_be_bytes());
+        buffer[8..12].copy_from_slice(&self.root_dispersion.to_be_bytes());
+        buffer[12..16].copy_from_slice(&self.reference_id);
+        buffer[16..24].copy_from_slice(&self.reference_timestamp.to_be_bytes());
+        buffer[24..32].copy_from_slice(&self.origin_timestamp.to_be_bytes());
+        buffer[32..40].copy_from_slice(&self.receive_timestamp.to_be_bytes());
+        buffer[40..48].copy_from_slice(&self.transmit_timestamp.to_be_bytes());
+        buffer
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Result<Self, String> {
+        if buffer.len() < NTP_PACKET_LEN {
+            return Err(format!("NTP packet too short: {} bytes", buffer.len()));
+        }
+        let u32_at = |i: usize| u32::from_be_bytes(buffer[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_be_bytes(buffer[i..i + 8].try_into().unwrap());
+        Ok(NtpPacket {
+            leap: buffer[0] >> 6,
+            version: (buffer[0] >> 3) & 0x07,
+            mode: buffer[0] & 0x07,
+            stratum: buffer[1],
+            poll: buffer[2] as i8,
+            precision: buffer[3] as i8,
+            root_delay: u32_at(4),
+            root_dispersion: u32_at(8),
+            reference_id: buffer[12..16].try_into().unwrap(),
+            reference_timestamp: u64_at(16),
+            origin_timestamp: u64_at(24),
+            receive_timestamp: u64_at(32),
+            transmit_timestamp: u64_at(40),
+        })
+    }
+}
+
+/// Result of one client/server exchange.
+#[derive(Debug, Clone, Copy)]
+pub struct NtpSample {
+    /// How far the server clock is ahead of ours.
+    pub offset: chrono::Duration,
+    pub delay: chrono::Duration,
+    /// Half the root delay plus the root dispersion: how far the server
+    /// itself may be from its reference clock.
+    pub root_distance: chrono::Duration,
+}
+
+impl NtpSample {
+    /// `t4` is our clock when the reply arrived.
+    pub fn into_sample(
+        self,
+        source: String,
+        t4: DateTime<Utc>,
+        sent: Instant,
+        received: Instant,
+    ) -> Sample {
+        Sample {
+            source,
+            server_time: t4 + self.offset,
+            local_time: t4,
+            sent,
+            received,
+            uncertainty: self.delay / 2 + self.root_distance,
+        }
+    }
+}
+
+/// SNTP client for a single server.
+pub struct NtpSource {
+    server: String,
+    timeout: Duration,
+}
+
+impl NtpSource {
+    pub fn new(server: &str, timeout: Duration) -> Self {
+        NtpSource {
+            server: server.to_string(),
+            timeout,
+        }
+    }
+}
+
+impl TimeSource for NtpSource {
+    fn sample(&mut self, clock: &dyn SystemClock) -> Result<Sample, SourceError> {
+        query_ntp_server(&self.server, self.timeout, clock)
+    }
+}
+
+/// Computes offset and round-trip delay from the four timestamps of an
+/// exchange: t1 client send, t2 server receive, t3 server send and t4 client
+/// receive.
+pub fn offset_and_delay(
+    t1: DateTime<Utc>,
+    t2: DateTime<Utc>,
+    t3: DateTime<Utc>,
+    t4: DateTime<Utc>,
+) -> (chrono::Duration, chrono::Duration) {
+    let offset = ((t2 - t1) + (t3 - t4)) / 2;
+    let delay = (t4 - t1) - (t3 - t2);
+    (offset, delay)
+}
+
+/// Checks a server reply against the request it answers and turns it into a
+/// sample.
+pub fn process_response(
+    request: &NtpPacket,
+    response: &NtpPacket,
+    t1: DateTime<Utc>,
+    t4: DateTime<Utc>,
+) -> Result<NtpSample, String> {
+    if response.mode != MODE_SERVER {
+        return Err(format!("unexpected NTP mode {}", response.mode));
+    }
+    if response.origin_timestamp != request.transmit_timestamp {
+        return Err("NTP response does not match our request".into());
+    }
+    if response.stratum == 0 {
+        return Err(format!(
+            "NTP server sent kiss-o'-death {}",
+            String::from_utf8_lossy(&response.reference_id)
+        ));
+    }
+    if response.leap == LEAP_UNSYNCHRONIZED || response.stratum > 15 {
+        return Err("NTP server is not synchronized".into());
+    }
+    if response.transmit_timestamp == 0 {
+        return Err("NTP response has no transmit timestamp".into());
+    }
+
+    let t2 = from_ntp_timestamp(response.receive_timestamp, t1);
+    let t3 = from_ntp_timestamp(response.transmit_timestamp, t1);
+    let (offset, delay) = offset_and_delay(t1, t2, t3, t4);
+
+    Ok(NtpSample {
+        offset,
+        delay,
+        root_distance: short_to_duration(response.root_delay) / 2
+            + short_to_duration(response.root_dispersion),
+    })
+}
+
+/// Sends a single SNTP request to `server` and measures offset and delay
+/// against `clock`.
+pub fn query_ntp_server(
+    server: &str,
+    timeout: Duration,
+    clock: &dyn SystemClock,
+) -> Result<Sample, SourceError> {
+    let addrs = server
+        .to_socket_addrs()
+        .map_err(|e| format!("Error resolving {}: {}", server, e))?;
+
+    let mut last_error = SourceError::from(format!("No addresses found for {}", server));
+    for addr in addrs {
+        let exchange = || -> Result<Sample, SourceError> {
+            let socket = net::udp_socket(addr, timeout)?;
+
+            let sent = Instant::now();
+            let t1 = clock.now();
+            let request = NtpPacket::client_request(t1);
+            socket.send(&request.to_bytes()).map_err(net::io_error)?;
+
+            let mut buffer = [0u8; 1024];
+            let bytes_read = socket.recv(&mut buffer).map_err(net::io_error)?;
+            let received = Instant::now();
+            let t4 = clock.now();
+
+            let response = NtpPacket::from_bytes(&buffer[..bytes_read])?;
+            let sample = process_response(&request, &response, t1, t4)?;
+            Ok(sample.into_sample(format!("ntp {} ({})", server, addr), t4, sent, received))
+        };
+        match exchange() {
+            Ok(sample) => return Ok(sample),
+            Err(e) => {
+                println!("Skipping NTP server {}: {}", addr, e);
+                last_error = e;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+// Converts the 16.16 fixed-point seconds used for root delay and dispersion.
+fn short_to_duration(value: u32) -> chrono::Duration {
+    chrono::Duration::nanoseconds(((value as u64 * 1_000_000_000) >> 16) as i64)
+}
+
+pub fn to_ntp_timestamp(datetime: DateTime<Utc>) -> u64 {
+    let seconds = (datetime.timestamp() + NTP_UNIX_OFFSET) as u64 & 0xFFFF_FFFF;
+    let fraction = ((datetime.timestamp_subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (seconds << 32) | fraction
+}
+
+/// Converts an NTP timestamp into a date, picking the 136-year era that puts
+/// it closest to `pivot` so the 2036 rollover is handled.
+pub fn from_ntp_timestamp(timestamp: u64, pivot: DateTime<Utc>) -> DateTime<Utc> {
+    let seconds = era_seconds((timestamp >> 32) as u32, pivot);
+    let nanos = ((timestamp & 0xFFFF_FFFF) * 1_000_000_000) >> 32;
+    DateTime::from_timestamp(seconds, nanos as u32).unwrap_or(pivot)
+}
+
+/// Unix seconds for a 32-bit count of seconds since 1900, resolved to the
+/// era closest to `pivot`.
+pub fn era_seconds(seconds: u32, pivot: DateTime<Utc>) -> i64 {
+    const ERA: i64 = 1 << 32;
+    let pivot_ntp = pivot.timestamp() + NTP_UNIX_OFFSET;
+    let mut ntp = pivot_ntp.div_euclid(ERA) * ERA + seconds as i64;
+    if ntp - pivot_ntp > ERA / 2 {
+        ntp -= ERA;
+    } else if pivot_ntp - ntp > ERA / 2 {
+        ntp += ERA;
+    }
+    ntp - NTP_UNIX_OFFSET
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::OsClock;
+    use std::{net::UdpSocket, thread};
+
+    fn at(seconds: i64, millis: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(seconds, 0).unwrap() + chrono::Duration::milliseconds(millis)
+    }
+
+    // A server reply to `request` from a clock `offset` ahead of ours.
+    fn reply(request: &NtpPacket, t1: DateTime<Utc>, offset: chrono::Duration) -> NtpPacket {
+        NtpPacket {
+            leap: 0,
+            version: NTP_VERSION,
+            mode: MODE_SERVER,
+            stratum: 1,
+            reference_id: *b"NIST",
+            origin_timestamp: request.transmit_timestamp,
+            receive_timestamp: to_ntp_timestamp(t1 + offset + chrono::Duration::milliseconds(5)),
+            transmit_timestamp: to_ntp_timestamp(t1 + offset + chrono::Duration::milliseconds(6)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn computes_offset_and_delay() {
+        // Server 10 s ahead, 500 ms each way, 100 ms spent in the server.
+        let (offset, delay) = offset_and_delay(at(0, 0), at(10, 500), at(10, 600), at(1, 100));
+        assert_eq!(offset, chrono::Duration::seconds(10));
+        assert_eq!(delay, chrono::Duration::seconds(1));
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        let time = at(1_700_000_000, 123) + chrono::Duration::nanoseconds(456_789);
+        let back = from_ntp_timestamp(to_ntp_timestamp(time), time);
+        assert!((back - time).abs() <= chrono::Duration::nanoseconds(1));
+    }
+
+    #[test]
+    fn resolves_the_2036_rollover() {
+        // NTP seconds wrap to 0 at 2036-02-07 06:28:16 UTC.
+        let rollover = (1i64 << 32) - NTP_UNIX_OFFSET;
+        assert_eq!(era_seconds(10, at(rollover + 20, 0)), rollover + 10);
+        assert_eq!(era_seconds(u32::MAX, at(rollover + 20, 0)), rollover - 1);
+        assert_eq!(era_seconds(10, at(rollover - 20, 0)), rollover + 10);
+        assert_eq!(
+            era_seconds(
+                (1_700_000_000 + NTP_UNIX_OFFSET) as u32,
+                at(1_700_000_000, 0)
+            ),
+            1_700_000_000
+        );
+    }
+
+    #[test]
+    fn processes_a_valid_response() {
+        let t1 = at(1_700_000_000, 0);
+        let request = NtpPacket::client_request(t1);
+        let response = reply(&request, t1, chrono::Duration::seconds(3));
+        let sample = process_response(
+            &request,
+            &response,
+            t1,
+            t1 + chrono::Duration::milliseconds(20),
+        )
+        .unwrap();
+        // Reaching the server takes 5 ms and coming back 14 ms, but the
+        // round trip is assumed symmetric, putting the offset 4.5 ms short.
+        assert!(
+            (sample.offset - chrono::Duration::microseconds(2_995_500)).abs()
+                <= chrono::Duration::microseconds(1)
+        );
+        assert!(
+            (sample.delay - chrono::Duration::milliseconds(19)).abs()
+                <= chrono::Duration::microseconds(1)
+        );
+    }
+
+    #[test]
+    fn rejects_kiss_o_death() {
+        let t1 = at(1_700_000_000, 0);
+        let request = NtpPacket::client_request(t1);
+        let response = NtpPacket {
+            stratum: 0,
+            reference_id: *b"RATE",
+            ..reply(&request, t1, chrono::Duration::zero())
+        };
+        let error = process_response(&request, &response, t1, t1).unwrap_err();
+        assert!(error.contains("RATE"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_responses_to_other_requests() {
+        let t1 = at(1_700_000_000, 0);
+        let request = NtpPacket::client_request(t1);
+        let response = NtpPacket {
+            origin_timestamp: request.transmit_timestamp + 1,
+            ..reply(&request, t1, chrono::Duration::zero())
+        };
+        assert!(process_response(&request, &response, t1, t1).is_err());
+    }
+
+    #[test]
+    fn rejects_unsynchronized_servers_and_wrong_modes() {
+        let t1 = at(1_700_000_000, 0);
+        let request = NtpPacket::client_request(t1);
+        let good = reply(&request, t1, chrono::Duration::zero());
+        for response in [
+            NtpPacket {
+                leap: LEAP_UNSYNCHRONIZED,
+                ..good
+            },
+            NtpPacket {
+                stratum: 16,
+                ..good
+            },
+            NtpPacket {
+                mode: MODE_CLIENT,
+                ..good
+            },
+            NtpPacket {
+                transmit_timestamp: 0,
+                ..good
+            },
+        ] {
+            assert!(process_response(&request, &response, t1, t1).is_err());
+        }
+    }
+
+    #[test]
+    fn packets_round_trip_through_bytes() {
+        let t1 = at(1_700_000_000, 250);
+        let request = NtpPacket::client_request(t1);
+        let packet = NtpPacket {
+            poll: -6,
+            precision: -20,
+            root_delay: 0x0001_8000,
+            root_dispersion: 0x0000_4000,
+            ..reply(&request, t1, chrono::Duration::seconds(1))
+        };
+        assert_eq!(NtpPacket::from_bytes(&packet.to_bytes()), Ok(packet));
+        assert!(NtpPacket::from_bytes(&[0u8; 47]).is_err());
+    }
+
+    // Answers one request like a server whose clock is `offset` ahead.
+    fn stand_in_server(offset: chrono::Duration) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buffer = [0u8; NTP_PACKET_LEN];
+            let (_, client) = socket.recv_from(&mut buffer).unwrap();
+            let request = NtpPacket::from_bytes(&buffer).unwrap();
+            let now = Utc::now() + offset;
+            let response = NtpPacket {
+                leap: 0,
+                version: NTP_VERSION,
+                mode: MODE_SERVER,
+                stratum: 1,
+                reference_id: *b"TEST",
+                origin_timestamp: request.transmit_timestamp,
+                receive_timestamp: to_ntp_timestamp(now),
+                transmit_timestamp: to_ntp_timestamp(now),
+                ..Default::default()
+            };
+            socket.send_to(&response.to_bytes(), client).unwrap();
+        });
+        address
+    }
+
+    #[test]
+    fn queries_a_local_server() {
+        let server = stand_in_server(chrono::Duration::milliseconds(2500));
+        let sample = query_ntp_server(&server, Duration::from_secs(2), &OsClock).unwrap();
+        assert!(
+            (sample.offset() - chrono::Duration::milliseconds(2500)).abs()
+                < chrono::Duration::milliseconds(50),
+            "{:?}",
+            sample.offset()
+        );
+        assert!(sample.delay() < chrono::Duration::milliseconds(50));
+    }
+
+    #[test]
+    fn times_out_when_the_server_is_silent() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap().to_string();
+        assert!(matches!(
+            query_ntp_server(&server, Duration::from_millis(200), &OsClock),
+            Err(SourceError::Timeout(_))
+        ));
+    }
+}
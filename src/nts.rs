@@ -0,0 +1,691 @@
+use crate::{
+    clock::SystemClock,
+    net,
+    ntp::{self, NtpPacket, NTP_PACKET_LEN},
+    source::{Sample, SourceError, TimeSource},
+};
+use aes_siv::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128SivAead, Nonce,
+};
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use rustls_pki_types::{pem::PemObject, CertificateDer};
+use std::{
+    io::{Read, Write},
+    net::ToSocketAddrs,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const NTS_KE_PORT: u16 = 4460;
+const NTP_PORT: u16 = 123;
+const NTS_KE_ALPN: &[u8] = b"ntske/1";
+const NTS_EXPORTER_LABEL: &[u8] = b"EXPORTER-network-time-security";
+// How many cookies we try to keep in stock, as recommended by RFC 8915.
+const COOKIE_TARGET: usize = 8;
+
+const PROTOCOL_NTPV4: u16 = 0;
+const AEAD_AES_SIV_CMAC_256: u16 = 15;
+const AEAD_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 16;
+const UNIQUE_ID_LEN: usize = 32;
+
+// NTS-KE record types (RFC 8915, section 4).
+const RECORD_END_OF_MESSAGE: u16 = 0;
+const RECORD_NEXT_PROTOCOL: u16 = 1;
+const RECORD_ERROR: u16 = 2;
+const RECORD_WARNING: u16 = 3;
+const RECORD_AEAD_ALGORITHM: u16 = 4;
+const RECORD_NEW_COOKIE: u16 = 5;
+const RECORD_SERVER: u16 = 6;
+const RECORD_PORT: u16 = 7;
+const RECORD_CRITICAL: u16 = 0x8000;
+
+// NTP extension fields used by NTS (RFC 8915, section 5).
+const EXT_UNIQUE_IDENTIFIER: u16 = 0x0104;
+const EXT_COOKIE: u16 = 0x0204;
+const EXT_COOKIE_PLACEHOLDER: u16 = 0x0304;
+const EXT_AUTHENTICATOR: u16 = 0x0404;
+
+struct ExtensionField<'a> {
+    field_type: u16,
+    // Start of the field relative to the start of the whole packet.
+    offset: usize,
+    body: &'a [u8],
+}
+
+/// Keys and cookies obtained from an NTS-KE handshake.
+struct NtsSession {
+    ntp_server: String,
+    c2s: Aes128SivAead,
+    s2c: Aes128SivAead,
+    cookies: Vec<Vec<u8>>,
+}
+
+/// Client for NTS-protected NTPv4. Runs the key exchange on first use and
+/// again whenever it runs out of cookies or the server rejects them.
+pub struct NtsClient {
+    host: String,
+    port: u16,
+    tls_config: Arc<ClientConfig>,
+    timeouts: net::Timeouts,
+    session: Option<NtsSession>,
+}
+
+impl NtsClient {
+    /// `server` is the NTS-KE server as `host[:port]`. Certificates are
+    /// checked against the bundled web roots plus the PEM file in `ca_file`.
+    pub fn new(
+        server: &str,
+        ca_file: Option<&Path>,
+        timeouts: net::Timeouts,
+    ) -> Result<Self, String> {
+        let (host, port) = net::split_host_port(server, NTS_KE_PORT)?;
+
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        if let Some(ca_file) = ca_file {
+            let certificates = CertificateDer::pem_file_iter(ca_file)
+                .map_err(|e| format!("Error reading {}: {}", ca_file.display(), e))?;
+            for certificate in certificates {
+                let certificate = certificate
+                    .map_err(|e| format!("Error reading {}: {}", ca_file.display(), e))?;
+                roots
+                    .add(certificate)
+                    .map_err(|e| format!("Invalid CA in {}: {}", ca_file.display(), e))?;
+            }
+        }
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls_config = ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls_config.alpn_protocols = vec![NTS_KE_ALPN.to_vec()];
+
+        Ok(NtsClient {
+            host,
+            port,
+            tls_config: Arc::new(tls_config),
+            timeouts,
+            session: None,
+        })
+    }
+
+    fn key_exchange(&self) -> Result<NtsSession, SourceError> {
+        let server_name = ServerName::try_from(self.host.clone())
+            .map_err(|e| format!("Invalid NTS-KE server name {}: {}", self.host, e))?;
+        let connection = ClientConnection::new(self.tls_config.clone(), server_name)
+            .map_err(|e| e.to_string())?;
+
+        let addrs = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Error resolving {}: {}", self.host, e))?;
+        let mut socket = Err(SourceError::from(format!(
+            "No addresses found for {}",
+            self.host
+        )));
+        for addr in addrs {
+            socket = net::tcp_connect(addr, self.timeouts).map_err(net::io_error);
+            if socket.is_ok() {
+                break;
+            }
+        }
+        let socket = socket?;
+        let mut stream = StreamOwned::new(connection, socket);
+
+        let mut request = Vec::new();
+        push_record(
+            &mut request,
+            RECORD_NEXT_PROTOCOL | RECORD_CRITICAL,
+            &PROTOCOL_NTPV4.to_be_bytes(),
+        );
+        push_record(
+            &mut request,
+            RECORD_AEAD_ALGORITHM | RECORD_CRITICAL,
+            &AEAD_AES_SIV_CMAC_256.to_be_bytes(),
+        );
+        push_record(&mut request, RECORD_END_OF_MESSAGE | RECORD_CRITICAL, &[]);
+        stream
+            .write_all(&request)
+            .and_then(|_| stream.flush())
+            .map_err(ke_error)?;
+
+        let mut cookies = Vec::new();
+        let mut ntp_host = self.host.clone();
+        let mut ntp_port = NTP_PORT;
+        let mut protocol_agreed = false;
+        let mut aead_agreed = false;
+        loop {
+            let mut header = [0u8; 4];
+            stream.read_exact(&mut header).map_err(ke_error)?;
+            let record_type = u16::from_be_bytes([header[0], header[1]]);
+            let mut body = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+            stream.read_exact(&mut body).map_err(ke_error)?;
+
+            match record_type & !RECORD_CRITICAL {
+                RECORD_END_OF_MESSAGE => break,
+                RECORD_NEXT_PROTOCOL => {
+                    protocol_agreed = body.chunks(2).any(|id| id == PROTOCOL_NTPV4.to_be_bytes());
+                }
+                RECORD_ERROR => {
+                    return Err(format!("NTS-KE server returned error {}", read_u16(&body)?).into());
+                }
+                RECORD_WARNING => {
+                    println!("NTS-KE server returned warning {}", read_u16(&body)?);
+                }
+                RECORD_AEAD_ALGORITHM => {
+                    aead_agreed = read_u16(&body)? == AEAD_AES_SIV_CMAC_256;
+                }
+                RECORD_NEW_COOKIE => cookies.push(body),
+                RECORD_SERVER => {
+                    ntp_host = String::from_utf8(body)
+                        .map_err(|_| "NTS-KE server sent an invalid NTP server name")?;
+                }
+                RECORD_PORT => ntp_port = read_u16(&body)?,
+                unknown if record_type & RECORD_CRITICAL != 0 => {
+                    return Err(
+                        format!("NTS-KE server sent unknown critical record {}", unknown).into(),
+                    );
+                }
+                _ => (),
+            }
+        }
+
+        if !protocol_agreed {
+            return Err("NTS-KE server does not support NTPv4".into());
+        }
+        if !aead_agreed {
+            return Err("NTS-KE server does not support AEAD_AES_SIV_CMAC_256".into());
+        }
+        if cookies.is_empty() {
+            return Err("NTS-KE server did not send any cookies".into());
+        }
+
+        let export = |direction: u8| -> Result<Aes128SivAead, String> {
+            let mut context = Vec::with_capacity(5);
+            context.extend_from_slice(&PROTOCOL_NTPV4.to_be_bytes());
+            context.extend_from_slice(&AEAD_AES_SIV_CMAC_256.to_be_bytes());
+            context.push(direction);
+            let key = stream
+                .conn
+                .export_keying_material([0u8; AEAD_KEY_LEN], NTS_EXPORTER_LABEL, Some(&context))
+                .map_err(|e| e.to_string())?;
+            Aes128SivAead::new_from_slice(&key).map_err(|e| e.to_string())
+        };
+
+        Ok(NtsSession {
+            ntp_server: net::join_host_port(&ntp_host, ntp_port),
+            c2s: export(0)?,
+            s2c: export(1)?,
+            cookies,
+        })
+    }
+}
+
+impl TimeSource for NtsClient {
+    /// Performs one authenticated NTP exchange.
+    fn sample(&mut self, clock: &dyn SystemClock) -> Result<Sample, SourceError> {
+        let session = match self.session.take() {
+            Some(session) if !session.cookies.is_empty() => self.session.insert(session),
+            _ => self.session.insert(self.key_exchange()?),
+        };
+        session.query(self.timeouts.read, clock)
+    }
+}
+
+impl NtsSession {
+    fn query(&mut self, timeout: Duration, clock: &dyn SystemClock) -> Result<Sample, SourceError> {
+        let cookie = self.cookies.pop().ok_or("No NTS cookies left")?;
+        let placeholders = COOKIE_TARGET.saturating_sub(self.cookies.len() + 1);
+
+        let unique_id = random_bytes(UNIQUE_ID_LEN)?;
+        let nonce = random_bytes(NONCE_LEN)?;
+        // The transmit timestamp is only used to match the reply, so it is
+        // random rather than leaking our clock.
+        let transmit = u64::from_be_bytes(random_bytes(8)?.try_into().unwrap());
+        let request = NtpPacket {
+            transmit_timestamp: transmit,
+            ..NtpPacket::client_request(clock.now())
+        };
+
+        let mut packet = request.to_bytes().to_vec();
+        push_extension(&mut packet, EXT_UNIQUE_IDENTIFIER, &unique_id);
+        push_extension(&mut packet, EXT_COOKIE, &cookie);
+        for _ in 0..placeholders {
+            push_extension(
+                &mut packet,
+                EXT_COOKIE_PLACEHOLDER,
+                &vec![0u8; cookie.len()],
+            );
+        }
+        authenticate(&mut packet, &self.c2s, &nonce, &[])?;
+
+        let addr = self
+            .ntp_server
+            .to_socket_addrs()
+            .map_err(|e| format!("Error resolving {}: {}", self.ntp_server, e))?
+            .next()
+            .ok_or_else(|| format!("No addresses found for {}", self.ntp_server))?;
+        let socket = net::udp_socket(addr, timeout)?;
+
+        let sent = Instant::now();
+        let t1 = clock.now();
+        socket.send(&packet).map_err(net::io_error)?;
+        let mut buffer = [0u8; 2048];
+        let bytes_read = socket.recv(&mut buffer).map_err(net::io_error)?;
+        let received = Instant::now();
+        let t4 = clock.now();
+
+        let reply = &buffer[..bytes_read];
+        let response = NtpPacket::from_bytes(reply)?;
+        let extensions = parse_extensions(&reply[NTP_PACKET_LEN..], NTP_PACKET_LEN)?;
+
+        let find = |field_type: u16| {
+            extensions
+                .iter()
+                .find(|field| field.field_type == field_type)
+        };
+        if !matches!(find(EXT_UNIQUE_IDENTIFIER), Some(field) if field.body == unique_id.as_slice())
+        {
+            return Err("NTS response does not match our request".into());
+        }
+        if response.stratum == 0 && &response.reference_id == b"NTSN" {
+            // The server rotated its keys, so the next query starts with a
+            // fresh key exchange.
+            self.cookies.clear();
+            return Err("NTS server rejected our cookie (NTSN)".into());
+        }
+
+        let authenticator = find(EXT_AUTHENTICATOR).ok_or("NTS response is not authenticated")?;
+        let plaintext = verify(reply, &self.s2c, authenticator)
+            .map_err(|_| "NTS response failed authentication")?;
+
+        for field in parse_extensions(&plaintext, 0)? {
+            if field.field_type == EXT_COOKIE {
+                self.cookies.push(field.body.to_vec());
+            }
+        }
+
+        let sample = ntp::process_response(&request, &response, t1, t4)?;
+        Ok(sample.into_sample(format!("nts {}", self.ntp_server), t4, sent, received))
+    }
+}
+
+fn ke_error(error: std::io::Error) -> SourceError {
+    match net::io_error(error) {
+        SourceError::Failed(message) => SourceError::Failed(format!("NTS-KE error: {}", message)),
+        timeout => timeout,
+    }
+}
+
+fn push_record(buffer: &mut Vec<u8>, record_type: u16, body: &[u8]) {
+    buffer.extend_from_slice(&record_type.to_be_bytes());
+    buffer.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(body);
+}
+
+// Appends an NTP extension field, padding the body to a multiple of 4 bytes.
+fn push_extension(buffer: &mut Vec<u8>, field_type: u16, body: &[u8]) {
+    let padded = body.len().div_ceil(4) * 4;
+    buffer.extend_from_slice(&field_type.to_be_bytes());
+    buffer.extend_from_slice(&((4 + padded) as u16).to_be_bytes());
+    buffer.extend_from_slice(body);
+    buffer.resize(buffer.len() + padded - body.len(), 0);
+}
+
+// Appends an authenticator covering everything in `packet` so far, with
+// `plaintext` (extension fields only the other side may read) encrypted in it.
+fn authenticate(
+    packet: &mut Vec<u8>,
+    key: &Aes128SivAead,
+    nonce: &[u8],
+    plaintext: &[u8],
+) -> Result<(), String> {
+    let nonce = Nonce::try_from(nonce).map_err(|_| "Unsupported NTS nonce length")?;
+    let ciphertext = key
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: packet,
+            },
+        )
+        .map_err(|_| "Error encrypting NTS packet")?;
+    push_extension(
+        packet,
+        EXT_AUTHENTICATOR,
+        &authenticator_body(nonce.as_slice(), &ciphertext),
+    );
+    Ok(())
+}
+
+// Checks the `authenticator` field of `packet` and returns what it encrypted.
+fn verify(
+    packet: &[u8],
+    key: &Aes128SivAead,
+    authenticator: &ExtensionField,
+) -> Result<Vec<u8>, String> {
+    let (nonce, ciphertext) = parse_authenticator(authenticator.body)?;
+    key.decrypt(
+        &Nonce::try_from(nonce).map_err(|_| "Unsupported NTS nonce length")?,
+        Payload {
+            msg: ciphertext,
+            aad: &packet[..authenticator.offset],
+        },
+    )
+    .map_err(|_| "NTS packet failed authentication".into())
+}
+
+fn authenticator_body(nonce: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+    body.extend_from_slice(&(ciphertext.len() as u16).to_be_bytes());
+    body.extend_from_slice(nonce);
+    body.resize(4 + nonce.len().div_ceil(4) * 4, 0);
+    body.extend_from_slice(ciphertext);
+    body
+}
+
+fn parse_authenticator(body: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    let invalid = || "Invalid NTS authenticator".to_string();
+    if body.len() < 4 {
+        return Err(invalid());
+    }
+    let nonce_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+    let ciphertext_len = u16::from_be_bytes([body[2], body[3]]) as usize;
+    let ciphertext_start = 4 + nonce_len.div_ceil(4) * 4;
+    let nonce = body.get(4..4 + nonce_len).ok_or_else(invalid)?;
+    let ciphertext = body
+        .get(ciphertext_start..ciphertext_start + ciphertext_len)
+        .ok_or_else(invalid)?;
+    Ok((nonce, ciphertext))
+}
+
+// Splits the NTP extension fields in `data`, which starts `base` bytes into
+// the packet.
+fn parse_extensions(data: &[u8], base: usize) -> Result<Vec<ExtensionField<'_>>, String> {
+    let mut fields = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = data
+            .get(offset..offset + 4)
+            .ok_or("Truncated NTP extension field")?;
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        if length < 4 || !length.is_multiple_of(4) || offset + length > data.len() {
+            return Err("Invalid NTP extension field length".into());
+        }
+        fields.push(ExtensionField {
+            field_type: u16::from_be_bytes([header[0], header[1]]),
+            offset: base + offset,
+            body: &data[offset + 4..offset + length],
+        });
+        offset += length;
+    }
+    Ok(fields)
+}
+
+fn read_u16(body: &[u8]) -> Result<u16, String> {
+    match body {
+        [high, low, ..] => Ok(u16::from_be_bytes([*high, *low])),
+        _ => Err("Truncated NTS-KE record".into()),
+    }
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>, String> {
+    let mut buffer = vec![0u8; len];
+    getrandom::getrandom(&mut buffer).map_err(|e| e.to_string())?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::OsClock;
+    use chrono::Utc;
+    use rustls::{ServerConfig, ServerConnection};
+    use rustls_pki_types::PrivateKeyDer;
+    use std::{
+        net::{TcpListener, UdpSocket},
+        thread,
+    };
+
+    const CA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/nts-ca.pem");
+    const SERVER_CERTIFICATE: &[u8] = include_bytes!("../testdata/nts-server.pem");
+    const SERVER_KEY: &[u8] = include_bytes!("../testdata/nts-server.key");
+
+    fn key(byte: u8) -> Aes128SivAead {
+        Aes128SivAead::new_from_slice(&[byte; AEAD_KEY_LEN]).unwrap()
+    }
+
+    #[test]
+    fn extension_fields_round_trip() {
+        let mut packet = vec![0u8; NTP_PACKET_LEN];
+        push_extension(&mut packet, EXT_UNIQUE_IDENTIFIER, &[1, 2, 3]);
+        push_extension(&mut packet, EXT_COOKIE, &[4, 5, 6, 7]);
+        push_extension(&mut packet, EXT_COOKIE_PLACEHOLDER, &[]);
+        assert_eq!(packet.len(), NTP_PACKET_LEN + 8 + 8 + 4);
+
+        let fields = parse_extensions(&packet[NTP_PACKET_LEN..], NTP_PACKET_LEN).unwrap();
+        let summary: Vec<(u16, usize, &[u8])> = fields
+            .iter()
+            .map(|field| (field.field_type, field.offset, field.body))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (EXT_UNIQUE_IDENTIFIER, 48, &[1u8, 2, 3, 0][..]),
+                (EXT_COOKIE, 56, &[4, 5, 6, 7][..]),
+                (EXT_COOKIE_PLACEHOLDER, 64, &[][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_extension_fields() {
+        // Truncated header.
+        assert!(parse_extensions(&[0x01, 0x04, 0x00], 0).is_err());
+        // Length not a multiple of 4, shorter than the header, past the end.
+        assert!(parse_extensions(&[0x01, 0x04, 0x00, 0x06, 0, 0], 0).is_err());
+        assert!(parse_extensions(&[0x01, 0x04, 0x00, 0x00], 0).is_err());
+        assert!(parse_extensions(&[0x01, 0x04, 0x00, 0x0c, 0, 0, 0, 0], 0).is_err());
+    }
+
+    #[test]
+    fn authenticator_round_trips() {
+        let nonce = [7u8; NONCE_LEN];
+        let ciphertext = [9u8; 21];
+        let body = authenticator_body(&nonce, &ciphertext);
+        assert_eq!(
+            parse_authenticator(&body),
+            Ok((&nonce[..], &ciphertext[..]))
+        );
+        assert!(parse_authenticator(&body[..body.len() - 1]).is_err());
+        assert!(parse_authenticator(&body[..3]).is_err());
+    }
+
+    #[test]
+    fn seals_and_opens_a_request() {
+        let mut packet = NtpPacket::client_request(Utc::now()).to_bytes().to_vec();
+        push_extension(&mut packet, EXT_UNIQUE_IDENTIFIER, &[1; UNIQUE_ID_LEN]);
+        push_extension(&mut packet, EXT_COOKIE, b"cookie");
+        let mut plaintext = Vec::new();
+        push_extension(&mut plaintext, EXT_COOKIE, b"new cookie");
+        authenticate(&mut packet, &key(1), &[3; NONCE_LEN], &plaintext).unwrap();
+
+        let open = |packet: &[u8], key: &Aes128SivAead| {
+            let fields = parse_extensions(&packet[NTP_PACKET_LEN..], NTP_PACKET_LEN).unwrap();
+            let authenticator = fields.last().unwrap();
+            assert_eq!(authenticator.field_type, EXT_AUTHENTICATOR);
+            verify(packet, key, authenticator)
+        };
+        assert_eq!(open(&packet, &key(1)), Ok(plaintext));
+        assert!(open(&packet, &key(2)).is_err());
+
+        // Any change to the authenticated part breaks it.
+        let mut tampered = packet.clone();
+        tampered[NTP_PACKET_LEN + 4] ^= 1;
+        assert!(open(&tampered, &key(1)).is_err());
+    }
+
+    fn export_key(connection: &ServerConnection, direction: u8) -> Aes128SivAead {
+        let mut context = Vec::new();
+        context.extend_from_slice(&PROTOCOL_NTPV4.to_be_bytes());
+        context.extend_from_slice(&AEAD_AES_SIV_CMAC_256.to_be_bytes());
+        context.push(direction);
+        let key = connection
+            .export_keying_material([0u8; AEAD_KEY_LEN], NTS_EXPORTER_LABEL, Some(&context))
+            .unwrap();
+        Aes128SivAead::new_from_slice(&key).unwrap()
+    }
+
+    // An NTS-KE server with a certificate from the test CA, and the NTP
+    // server it hands out, whose clock is `offset` ahead. Serves one key
+    // exchange and one query.
+    fn stand_in_server(offset: chrono::Duration) -> String {
+        let ke = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ntp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let ke_address = ke.local_addr().unwrap().to_string();
+        let ntp_port = ntp.local_addr().unwrap().port();
+
+        let certificates = CertificateDer::pem_slice_iter(SERVER_CERTIFICATE)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_slice(SERVER_KEY).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .unwrap();
+        config.alpn_protocols = vec![NTS_KE_ALPN.to_vec()];
+
+        thread::spawn(move || {
+            let (socket, _) = ke.accept().unwrap();
+            let connection = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut stream = StreamOwned::new(connection, socket);
+            loop {
+                let mut header = [0u8; 4];
+                stream.read_exact(&mut header).unwrap();
+                let mut body = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+                stream.read_exact(&mut body).unwrap();
+                if u16::from_be_bytes([header[0], header[1]]) & !RECORD_CRITICAL
+                    == RECORD_END_OF_MESSAGE
+                {
+                    break;
+                }
+            }
+            let mut response = Vec::new();
+            push_record(
+                &mut response,
+                RECORD_NEXT_PROTOCOL | RECORD_CRITICAL,
+                &PROTOCOL_NTPV4.to_be_bytes(),
+            );
+            push_record(
+                &mut response,
+                RECORD_AEAD_ALGORITHM,
+                &AEAD_AES_SIV_CMAC_256.to_be_bytes(),
+            );
+            push_record(&mut response, RECORD_NEW_COOKIE, b"cookie 1");
+            push_record(&mut response, RECORD_NEW_COOKIE, b"cookie 2");
+            push_record(&mut response, RECORD_SERVER, b"127.0.0.1");
+            push_record(&mut response, RECORD_PORT, &ntp_port.to_be_bytes());
+            push_record(&mut response, RECORD_END_OF_MESSAGE | RECORD_CRITICAL, &[]);
+            stream.write_all(&response).unwrap();
+            stream.flush().unwrap();
+            let c2s = export_key(&stream.conn, 0);
+            let s2c = export_key(&stream.conn, 1);
+
+            let mut buffer = [0u8; 2048];
+            let (length, client) = ntp.recv_from(&mut buffer).unwrap();
+            let request_bytes = &buffer[..length];
+            let request = NtpPacket::from_bytes(request_bytes).unwrap();
+            let fields =
+                parse_extensions(&request_bytes[NTP_PACKET_LEN..], NTP_PACKET_LEN).unwrap();
+            let authenticator = fields
+                .iter()
+                .find(|field| field.field_type == EXT_AUTHENTICATOR)
+                .unwrap();
+            verify(request_bytes, &c2s, authenticator).unwrap();
+            let unique_id = fields
+                .iter()
+                .find(|field| field.field_type == EXT_UNIQUE_IDENTIFIER)
+                .unwrap();
+            let cookie = fields
+                .iter()
+                .find(|field| field.field_type == EXT_COOKIE)
+                .unwrap();
+            assert!(cookie.body.starts_with(b"cookie "));
+
+            let now = Utc::now() + offset;
+            let mut response = NtpPacket {
+                leap: 0,
+                version: 4,
+                mode: 4,
+                stratum: 1,
+                reference_id: *b"TEST",
+                origin_timestamp: request.transmit_timestamp,
+                receive_timestamp: ntp::to_ntp_timestamp(now),
+                transmit_timestamp: ntp::to_ntp_timestamp(now),
+                ..Default::default()
+            }
+            .to_bytes()
+            .to_vec();
+            push_extension(&mut response, EXT_UNIQUE_IDENTIFIER, unique_id.body);
+            let mut plaintext = Vec::new();
+            push_extension(&mut plaintext, EXT_COOKIE, b"cookie 3");
+            authenticate(&mut response, &s2c, &[5; NONCE_LEN], &plaintext).unwrap();
+            ntp.send_to(&response, client).unwrap();
+        });
+        ke_address
+    }
+
+    #[test]
+    fn syncs_with_a_local_nts_server() {
+        let server = stand_in_server(chrono::Duration::milliseconds(2500));
+        let timeouts = net::Timeouts {
+            connect: Duration::from_secs(2),
+            read: Duration::from_secs(2),
+        };
+        let mut client = NtsClient::new(&server, Some(Path::new(CA)), timeouts).unwrap();
+        let sample = client.sample(&OsClock).unwrap();
+        assert!(
+            (sample.offset() - chrono::Duration::milliseconds(2500)).abs()
+                < chrono::Duration::milliseconds(50),
+            "{:?}",
+            sample.offset()
+        );
+        // One of the two cookies was spent and the reply brought a new one.
+        let cookies = &client.session.as_ref().unwrap().cookies;
+        assert_eq!(cookies.len(), 2);
+        assert!(cookies.contains(&b"cookie 3".to_vec()));
+    }
+
+    #[test]
+    fn rejects_servers_outside_the_trusted_roots() {
+        let server = stand_in_server(chrono::Duration::zero());
+        let timeouts = net::Timeouts {
+            connect: Duration::from_secs(2),
+            read: Duration::from_secs(2),
+        };
+        let mut client = NtsClient::new(&server, None, timeouts).unwrap();
+        assert!(client.sample(&OsClock).is_err());
+    }
+
+    #[test]
+    fn times_out_on_a_silent_key_exchange_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let timeouts = net::Timeouts {
+            connect: Duration::from_secs(2),
+            read: Duration::from_millis(100),
+        };
+        let mut client = NtsClient::new(&server, Some(Path::new(CA)), timeouts).unwrap();
+        let error = client.sample(&OsClock).unwrap_err();
+        assert!(matches!(error, SourceError::Timeout(_)), "{:?}", error);
+    }
+}
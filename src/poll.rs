@@ -0,0 +1,165 @@
+use crate::clock::seconds;
+use std::time::Duration;
+
+// Offsets below this many times the jitter count as the clock being stable.
+const POLL_GATE: f64 = 4.0;
+// How far the counter has to move before the poll exponent changes.
+const POLL_LIMIT: i32 = 30;
+// Weight of every new offset in the jitter average.
+const JITTER_AVERAGE: f64 = 4.0;
+// Floor for the jitter, so a run of identical offsets doesn't make the next
+// one that differs by a hair look unstable.
+const MIN_JITTER_SECS: f64 = 0.001;
+
+/// Interval as NTP expresses it, 2 to the power of `exponent` seconds.
+pub fn interval(exponent: u8) -> Duration {
+    Duration::from_secs(1 << exponent)
+}
+
+/// Picks the time until the next sync from how the offsets behave, like
+/// NTP's poll exponent (RFC 5905, section 11.3).
+///
+/// Syncs start at the shortest interval, so a freshly booted clock is brought
+/// in quickly. Every offset that stays within a few times the jitter moves
+/// the interval towards the longest one, every offset that doesn't moves it
+/// back twice as fast. Offsets within the uncertainty of the samples can't be
+/// told apart, so the jitter is never taken as smaller than that.
+pub struct PollInterval {
+    exponent: u8,
+    min_exponent: u8,
+    max_exponent: u8,
+    counter: i32,
+    // Root mean square of the differences between successive offsets, in
+    // seconds.
+    jitter: f64,
+    last_offset: Option<f64>,
+}
+
+impl PollInterval {
+    pub fn new(min_exponent: u8, max_exponent: u8) -> Self {
+        PollInterval {
+            exponent: min_exponent,
+            min_exponent,
+            max_exponent,
+            counter: 0,
+            jitter: 0.0,
+            last_offset: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        interval(self.exponent)
+    }
+
+    /// Feeds the offset measured this sync and how far off it may be.
+    pub fn update(&mut self, offset: chrono::Duration, uncertainty: chrono::Duration) {
+        let offset = seconds(offset);
+        let floor = seconds(uncertainty).max(MIN_JITTER_SECS);
+        if let Some(last_offset) = self.last_offset.replace(offset) {
+            let difference = (offset - last_offset).powi(2);
+            self.jitter =
+                (self.jitter.powi(2) + (difference - self.jitter.powi(2)) / JITTER_AVERAGE).sqrt();
+        }
+
+        let exponent = self.exponent as i32;
+        match offset.abs() < POLL_GATE * self.jitter.max(floor) {
+            true => {
+                self.counter += exponent;
+                if self.counter > POLL_LIMIT {
+                    self.counter = POLL_LIMIT;
+                    if self.exponent < self.max_exponent {
+                        self.counter = 0;
+                        self.exponent += 1;
+                    }
+                }
+            }
+            false => {
+                self.counter -= 2 * exponent;
+                if self.counter < -POLL_LIMIT {
+                    self.counter = -POLL_LIMIT;
+                    if self.exponent > self.min_exponent {
+                        self.counter = 0;
+                        self.exponent -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Goes back to the shortest interval, e.g. after the clock was found far
+    /// off.
+    pub fn reset(&mut self) {
+        self.exponent = self.min_exponent;
+        self.counter = 0;
+        self.last_offset = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::ms;
+
+    fn exponent(poll: &PollInterval) -> u32 {
+        poll.interval().as_secs().trailing_zeros()
+    }
+
+    #[test]
+    fn starts_at_the_shortest_interval() {
+        let poll = PollInterval::new(6, 10);
+        assert_eq!(poll.interval(), Duration::from_secs(64));
+    }
+
+    #[test]
+    fn lengthens_the_interval_while_offsets_are_stable() {
+        let mut poll = PollInterval::new(6, 10);
+        for i in 0..6 {
+            poll.update(ms(i % 2), ms(10));
+        }
+        assert_eq!(exponent(&poll), 7);
+        for _ in 0..100 {
+            poll.update(ms(1), ms(10));
+        }
+        assert_eq!(exponent(&poll), 10);
+    }
+
+    #[test]
+    fn shortens_the_interval_when_offsets_grow() {
+        let mut poll = PollInterval::new(6, 10);
+        for _ in 0..100 {
+            poll.update(ms(1), ms(10));
+        }
+        // Offsets growing by the same step every sync, like a clock drifting
+        // away faster than the interval allows to correct.
+        let mut offsets = (1..).map(|i| ms(20 * i));
+        for offset in offsets.by_ref().take(8) {
+            poll.update(offset, ms(10));
+        }
+        assert_eq!(exponent(&poll), 9);
+        for offset in offsets.take(20) {
+            poll.update(offset, ms(10));
+        }
+        assert_eq!(exponent(&poll), 6);
+    }
+
+    #[test]
+    fn treats_offsets_within_the_uncertainty_as_stable() {
+        // Daytime only has whole seconds, its offsets jump around by
+        // hundreds of milliseconds without the clock being any less stable.
+        let mut poll = PollInterval::new(6, 10);
+        for offset in [400, -300, 600, -700, 200, 0, -500, 800] {
+            poll.update(ms(offset), ms(1000));
+        }
+        assert_eq!(exponent(&poll), 7);
+    }
+
+    #[test]
+    fn reset_goes_back_to_the_shortest_interval() {
+        let mut poll = PollInterval::new(6, 10);
+        for _ in 0..100 {
+            poll.update(ms(1), ms(10));
+        }
+        poll.reset();
+        assert_eq!(exponent(&poll), 6);
+    }
+}
@@ -0,0 +1,266 @@
+use clap::ValueEnum;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+// Consecutive failures after which a server is left out for a while.
+const MAX_FAILURES: u32 = 3;
+const BENCH_TIME: Duration = Duration::from_secs(15 * 60);
+/// NIST refuses clients that query a server more than once every 4 seconds.
+pub const MIN_QUERY_SPACING: Duration = Duration::from_secs(4);
+// A server that refuses us is left alone for this long, doubling with every
+// refusal in a row.
+const REFUSAL_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_REFUSAL_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Order in which the servers of a pool are tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ServerOrder {
+    /// As listed, which keeps hitting the first server while it works
+    InOrder,
+    /// Shuffled on every sync, which spreads the load over the pool
+    Random,
+}
+
+/// Keeps the queries to one server at least `interval` apart, normally
+/// `MIN_QUERY_SPACING`.
+pub struct QuerySpacing {
+    interval: Duration,
+    last_query: Option<Instant>,
+}
+
+impl QuerySpacing {
+    pub fn new(interval: Duration) -> Self {
+        QuerySpacing {
+            interval,
+            last_query: None,
+        }
+    }
+
+    /// Waits until the server may be queried again without tripping its rate
+    /// limit, and notes that it is being queried now.
+    pub fn wait(&mut self) {
+        if let Some(last_query) = self.last_query {
+            thread::sleep(self.interval.saturating_sub(last_query.elapsed()));
+        }
+        self.last_query = Some(Instant::now());
+    }
+
+    #[cfg(test)]
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+struct Server {
+    address: String,
+    failures: u32,
+    refusals: u32,
+    benched_until: Option<Instant>,
+    refused_until: Option<Instant>,
+    spacing: QuerySpacing,
+}
+
+/// A list of interchangeable servers that remembers which ones have been
+/// failing, so a sync fails over to the next one instead of giving up.
+pub struct ServerPool {
+    servers: Vec<Server>,
+    order: ServerOrder,
+}
+
+impl ServerPool {
+    pub fn new(addresses: &[String], order: ServerOrder) -> Self {
+        ServerPool {
+            servers: addresses
+                .iter()
+                .map(|address| Server {
+                    address: address.clone(),
+                    failures: 0,
+                    refusals: 0,
+                    benched_until: None,
+                    refused_until: None,
+                    spacing: QuerySpacing::new(MIN_QUERY_SPACING),
+                })
+                .collect(),
+            order,
+        }
+    }
+
+    /// The servers to try for the next sample, best first: servers that have
+    /// not been failing, then the ones that have, then benched servers in
+    /// case nothing else answers. Servers backing off after a refusal are
+    /// left out, querying them again would only prolong it.
+    pub fn candidates(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut servers: Vec<&Server> = self
+            .servers
+            .iter()
+            .filter(|server| server.refused_until.is_none_or(|until| until <= now))
+            .collect();
+        if self.order == ServerOrder::Random {
+            shuffle(&mut servers);
+        }
+        servers.sort_by_key(|server| {
+            let benched = server.benched_until.is_some_and(|until| until > now);
+            (benched, server.failures > 0)
+        });
+        servers
+            .into_iter()
+            .map(|server| server.address.clone())
+            .collect()
+    }
+
+    /// Waits until `address` may be queried again without tripping its rate
+    /// limit, and notes that it is being queried now.
+    pub fn begin_query(&mut self, address: &str) {
+        if let Some(server) = self.find(address) {
+            server.spacing.wait();
+        }
+    }
+
+    pub fn record_success(&mut self, address: &str) {
+        if let Some(server) = self.find(address) {
+            server.failures = 0;
+            server.refusals = 0;
+            server.benched_until = None;
+            server.refused_until = None;
+        }
+    }
+
+    /// The server turned us away, which NIST servers do to clients they
+    /// think query too often, so back off instead of trying again soon.
+    pub fn record_refusal(&mut self, address: &str) {
+        if let Some(server) = self.find(address) {
+            server.refusals += 1;
+            let backoff = REFUSAL_BACKOFF
+                .saturating_mul(1 << (server.refusals - 1).min(16))
+                .min(MAX_REFUSAL_BACKOFF);
+            let minutes = backoff.as_secs() / 60;
+            println!(
+                "{} refused the query, it may be rate limiting us; backing off for {} {}",
+                server.address,
+                minutes,
+                match minutes {
+                    1 => "minute",
+                    _ => "minutes",
+                }
+            );
+            server.refused_until = Some(Instant::now() + backoff);
+        }
+    }
+
+    pub fn record_failure(&mut self, address: &str) {
+        if let Some(server) = self.find(address) {
+            server.failures += 1;
+            if server.failures >= MAX_FAILURES {
+                println!(
+                    "Benching {} for {} minutes after {} failures in a row",
+                    server.address,
+                    BENCH_TIME.as_secs() / 60,
+                    server.failures
+                );
+                server.benched_until = Some(Instant::now() + BENCH_TIME);
+            }
+        }
+    }
+
+    fn find(&mut self, address: &str) -> Option<&mut Server> {
+        self.servers
+            .iter_mut()
+            .find(|server| server.address == address)
+    }
+}
+
+// Fisher-Yates shuffle. Falls back to the configured order if the system has
+// no randomness to offer.
+fn shuffle<T>(items: &mut [T]) {
+    let mut random = vec![0u8; items.len() * 4];
+    if getrandom::getrandom(&mut random).is_err() {
+        return;
+    }
+    for i in (1..items.len()).rev() {
+        let bytes = [
+            random[i * 4],
+            random[i * 4 + 1],
+            random[i * 4 + 2],
+            random[i * 4 + 3],
+        ];
+        items.swap(i, u32::from_le_bytes(bytes) as usize % (i + 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(order: ServerOrder) -> ServerPool {
+        let addresses = ["a:13", "b:13", "c:13"].map(String::from);
+        ServerPool::new(&addresses, order)
+    }
+
+    #[test]
+    fn tries_servers_in_order() {
+        assert_eq!(
+            pool(ServerOrder::InOrder).candidates(),
+            ["a:13", "b:13", "c:13"]
+        );
+    }
+
+    #[test]
+    fn shuffles_every_server_in_random_order() {
+        let mut candidates = pool(ServerOrder::Random).candidates();
+        candidates.sort();
+        assert_eq!(candidates, ["a:13", "b:13", "c:13"]);
+    }
+
+    #[test]
+    fn tries_failing_servers_last() {
+        let mut pool = pool(ServerOrder::InOrder);
+        pool.record_failure("a:13");
+        assert_eq!(pool.candidates(), ["b:13", "c:13", "a:13"]);
+        pool.record_success("a:13");
+        assert_eq!(pool.candidates(), ["a:13", "b:13", "c:13"]);
+    }
+
+    #[test]
+    fn keeps_benched_servers_as_a_last_resort() {
+        let mut pool = pool(ServerOrder::InOrder);
+        for _ in 0..MAX_FAILURES {
+            pool.record_failure("a:13");
+        }
+        pool.record_failure("b:13");
+        assert_eq!(pool.candidates(), ["c:13", "b:13", "a:13"]);
+    }
+
+    #[test]
+    fn leaves_out_servers_that_refused_us() {
+        let mut pool = pool(ServerOrder::InOrder);
+        pool.record_refusal("b:13");
+        assert_eq!(pool.candidates(), ["a:13", "c:13"]);
+        pool.record_refusal("a:13");
+        pool.record_refusal("c:13");
+        assert!(pool.candidates().is_empty());
+    }
+
+    #[test]
+    fn spaces_queries_to_a_server() {
+        let mut spacing = QuerySpacing::new(Duration::from_millis(50));
+        let start = Instant::now();
+        spacing.wait();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        spacing.wait();
+        spacing.wait();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn spaces_queries_to_each_server_separately() {
+        let mut pool = pool(ServerOrder::InOrder);
+        let start = Instant::now();
+        pool.begin_query("a:13");
+        pool.begin_query("b:13");
+        pool.begin_query("c:13");
+        assert!(start.elapsed() < MIN_QUERY_SPACING);
+    }
+}
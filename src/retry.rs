@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+// First retry after a failed sync, doubling with every failure in a row.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Spaces out retries after failed syncs, so an outage isn't met with a
+/// stream of queries and the servers aren't hit by every client at once when
+/// it ends.
+pub struct Backoff {
+    failures: u32,
+    cap: Duration,
+}
+
+impl Backoff {
+    /// Retries never wait longer than `interval`, the time between regular
+    /// syncs.
+    pub fn new(interval: Duration) -> Self {
+        Backoff {
+            failures: 0,
+            cap: interval.min(MAX_RETRY_DELAY),
+        }
+    }
+
+    /// How long to wait before retrying the sync that just failed.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = RETRY_DELAY
+            .saturating_mul(1 << self.failures.min(16))
+            .min(self.cap);
+        self.failures += 1;
+        // Anywhere between half and all of the delay.
+        delay.mul_f64(0.5 + random_fraction() / 2.0)
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+// A number between 0 and 1, or 1 if the system has no randomness to offer.
+fn random_fraction() -> f64 {
+    let mut random = [0u8; 4];
+    match getrandom::getrandom(&mut random) {
+        Ok(()) => u32::from_le_bytes(random) as f64 / u32::MAX as f64,
+        Err(_) => 1.0,
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{
+    clock::SystemClock,
+    net,
+    ntp::era_seconds,
+    source::{Sample, SourceError, TimeSource},
+};
+use chrono::DateTime;
+use clap::ValueEnum;
+use std::{
+    io::Read,
+    net::{SocketAddr, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+struct TimeReply {
+    seconds: u32,
+    sent: Instant,
+    round_trip: Duration,
+    received: Instant,
+}
+
+/// RFC 868 Time protocol client.
+pub struct Rfc868Source {
+    server: String,
+    transport: Transport,
+    timeouts: net::Timeouts,
+}
+
+impl Rfc868Source {
+    pub fn new(server: &str, transport: Transport, timeouts: net::Timeouts) -> Self {
+        Rfc868Source {
+            server: server.to_string(),
+            transport,
+            timeouts,
+        }
+    }
+}
+
+impl TimeSource for Rfc868Source {
+    /// Fetches the 32-bit seconds-since-1900 value and estimates the UTC at
+    /// the moment it arrived.
+    fn sample(&mut self, clock: &dyn SystemClock) -> Result<Sample, SourceError> {
+        let addrs = self
+            .server
+            .to_socket_addrs()
+            .map_err(|e| format!("Error resolving {}: {}", self.server, e))?;
+
+        let mut last_error = SourceError::from(format!("No addresses found for {}", self.server));
+        for addr in addrs {
+            let reply = match self.transport {
+                Transport::Tcp => read_tcp(addr, self.timeouts),
+                Transport::Udp => read_udp(addr, self.timeouts.read),
+            };
+            let local_time = clock.now();
+            let time = reply.and_then(|reply| {
+                let time = DateTime::from_timestamp(era_seconds(reply.seconds, local_time), 0)
+                    .ok_or_else(|| format!("Invalid time {} from {}", reply.seconds, addr))?;
+                Ok((reply, time))
+            });
+            match time {
+                Ok((reply, time)) => {
+                    let one_way = chrono::Duration::from_std(reply.round_trip / 2)
+                        .unwrap_or(chrono::Duration::zero());
+                    // The server sent the whole second it was in, so it was
+                    // half a second past it on average.
+                    let half_second = chrono::Duration::milliseconds(500);
+                    return Ok(Sample {
+                        source: format!("time {} ({})", self.server, addr),
+                        server_time: time + half_second + one_way,
+                        local_time,
+                        sent: reply.sent,
+                        received: reply.received,
+                        uncertainty: half_second + one_way,
+                    });
+                }
+                Err(e) => {
+                    println!("Skipping time server {}: {}", addr, e);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+fn read_tcp(addr: SocketAddr, timeouts: net::Timeouts) -> Result<TimeReply, SourceError> {
+    let sent = Instant::now();
+    let mut stream = net::tcp_connect(addr, timeouts).map_err(net::io_error)?;
+    let round_trip = sent.elapsed();
+
+    let mut buffer = [0u8; 4];
+    stream.read_exact(&mut buffer).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => "Time server closed the connection early".into(),
+        _ => net::io_error(e),
+    })?;
+
+    Ok(TimeReply {
+        seconds: u32::from_be_bytes(buffer),
+        sent,
+        round_trip,
+        received: Instant::now(),
+    })
+}
+
+fn read_udp(addr: SocketAddr, timeout: Duration) -> Result<TimeReply, SourceError> {
+    let socket = net::udp_socket(addr, timeout)?;
+
+    // Any datagram, including an empty one, asks the server for the time.
+    let sent = Instant::now();
+    socket.send(&[]).map_err(net::io_error)?;
+
+    let mut buffer = [0u8; 16];
+    let bytes_read = socket.recv(&mut buffer).map_err(net::io_error)?;
+    let received = Instant::now();
+    if bytes_read != 4 {
+        return Err(format!("Time server sent {} bytes instead of 4", bytes_read).into());
+    }
+
+    Ok(TimeReply {
+        seconds: u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]),
+        sent,
+        round_trip: received - sent,
+        received,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+    use crate::ntp::to_ntp_timestamp;
+    use chrono::{TimeZone, Utc};
+    use std::{
+        io::Write,
+        net::{TcpListener, UdpSocket},
+        thread,
+    };
+
+    fn timeouts() -> net::Timeouts {
+        net::Timeouts {
+            connect: Duration::from_secs(2),
+            read: Duration::from_secs(2),
+        }
+    }
+
+    // Seconds since 1900 as the protocol sends them, wrapped to 32 bits.
+    fn seconds(time: DateTime<Utc>) -> [u8; 4] {
+        ((to_ntp_timestamp(time) >> 32) as u32).to_be_bytes()
+    }
+
+    // A TCP server that answers one connection with `reply`.
+    fn tcp_server(reply: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            socket.write_all(&reply).unwrap();
+        });
+        address
+    }
+
+    // A UDP server that answers one datagram with `reply`.
+    fn udp_server(reply: Vec<u8>) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 16];
+            let (_, client) = socket.recv_from(&mut buffer).unwrap();
+            socket.send_to(&reply, client).unwrap();
+        });
+        address
+    }
+
+    fn sample(
+        address: &str,
+        transport: Transport,
+        clock: &SimulatedClock,
+    ) -> Result<Sample, SourceError> {
+        Rfc868Source::new(address, transport, timeouts()).sample(clock)
+    }
+
+    fn assert_centred(sample: &Sample, time: DateTime<Utc>) {
+        let half_second = chrono::Duration::milliseconds(500);
+        let one_way = sample.uncertainty - half_second;
+        assert!(one_way >= chrono::Duration::zero() && one_way < chrono::Duration::seconds(1));
+        assert_eq!(sample.server_time, time + half_second + one_way);
+    }
+
+    #[test]
+    fn reads_the_time_over_tcp_and_udp() {
+        let time = Utc.with_ymd_and_hms(2024, 10, 15, 12, 34, 56).unwrap();
+        let clock = SimulatedClock::new(time, chrono::Duration::seconds(-30), 0.0);
+        for transport in [Transport::Tcp, Transport::Udp] {
+            let address = match transport {
+                Transport::Tcp => tcp_server(seconds(time).to_vec()),
+                Transport::Udp => udp_server(seconds(time).to_vec()),
+            };
+            let sample = sample(&address, transport, &clock).unwrap();
+            assert_centred(&sample, time);
+            assert_eq!(sample.local_time, time - chrono::Duration::seconds(30));
+        }
+    }
+
+    #[test]
+    fn resolves_the_era_after_2036() {
+        let time = Utc.with_ymd_and_hms(2036, 6, 1, 0, 0, 0).unwrap();
+        let clock = SimulatedClock::new(time, chrono::Duration::zero(), 0.0);
+        let address = tcp_server(seconds(time).to_vec());
+        let sample = sample(&address, Transport::Tcp, &clock).unwrap();
+        assert_centred(&sample, time);
+    }
+
+    #[test]
+    fn rejects_short_replies() {
+        let clock = SimulatedClock::new(Utc::now(), chrono::Duration::zero(), 0.0);
+        let address = tcp_server(vec![0xea, 0x9c]);
+        assert!(sample(&address, Transport::Tcp, &clock).is_err());
+        let address = udp_server(vec![0xea, 0x9c, 0x4a]);
+        assert!(sample(&address, Transport::Udp, &clock).is_err());
+    }
+}
@@ -0,0 +1,559 @@
+use crate::{clock::SystemClock, net, source::SourceError};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use sha2::{Digest, Sha512};
+use std::{net::ToSocketAddrs, str::FromStr, time::Duration};
+
+const REQUEST_LEN: usize = 1024;
+const NONCE_LEN: usize = 64;
+const HASH_LEN: usize = 64;
+const DELEGATION_CONTEXT: &[u8] = b"RoughTime v1 delegation signature--\x00";
+const RESPONSE_CONTEXT: &[u8] = b"RoughTime v1 response signature\x00";
+
+const TAG_PAD: u32 = u32::from_le_bytes(*b"PAD\xff");
+const TAG_NONC: u32 = u32::from_le_bytes(*b"NONC");
+const TAG_SIG: u32 = u32::from_le_bytes(*b"SIG\x00");
+const TAG_PATH: u32 = u32::from_le_bytes(*b"PATH");
+const TAG_SREP: u32 = u32::from_le_bytes(*b"SREP");
+const TAG_CERT: u32 = u32::from_le_bytes(*b"CERT");
+const TAG_INDX: u32 = u32::from_le_bytes(*b"INDX");
+const TAG_DELE: u32 = u32::from_le_bytes(*b"DELE");
+const TAG_PUBK: u32 = u32::from_le_bytes(*b"PUBK");
+const TAG_MINT: u32 = u32::from_le_bytes(*b"MINT");
+const TAG_MAXT: u32 = u32::from_le_bytes(*b"MAXT");
+const TAG_ROOT: u32 = u32::from_le_bytes(*b"ROOT");
+const TAG_MIDP: u32 = u32::from_le_bytes(*b"MIDP");
+const TAG_RADI: u32 = u32::from_le_bytes(*b"RADI");
+
+/// A Roughtime server and its long-term Ed25519 public key, written as
+/// `host:port=base64key`.
+#[derive(Debug, Clone)]
+pub struct RoughtimeServer {
+    pub address: String,
+    pub public_key: VerifyingKey,
+}
+
+impl FromStr for RoughtimeServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, key) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected host:port=base64key, got {:?}", s))?;
+        let key = BASE64
+            .decode(key)
+            .map_err(|e| format!("invalid Roughtime public key {:?}: {}", key, e))?;
+        let key: [u8; 32] = key
+            .try_into()
+            .map_err(|_| "Roughtime public key must be 32 bytes".to_string())?;
+        let public_key = VerifyingKey::from_bytes(&key)
+            .map_err(|e| format!("invalid Roughtime public key: {}", e))?;
+        Ok(RoughtimeServer {
+            address: address.to_string(),
+            public_key,
+        })
+    }
+}
+
+/// A verified Roughtime answer: the server's time was within `radius` of
+/// `midpoint` when it signed the response.
+#[derive(Debug, Clone, Copy)]
+pub struct RoughtimeSample {
+    pub midpoint: DateTime<Utc>,
+    pub radius: chrono::Duration,
+    /// Offset of the midpoint from our clock halfway through the exchange.
+    pub offset: chrono::Duration,
+    pub delay: chrono::Duration,
+}
+
+impl RoughtimeSample {
+    /// Whether a clock offset measured by another source is consistent with
+    /// this answer, allowing for the radius and half the round trip.
+    pub fn contains(&self, offset: chrono::Duration) -> bool {
+        (offset - self.offset).abs() <= self.radius + self.delay / 2
+    }
+}
+
+/// Sends a nonce to `server` and verifies the signed, Merkle-proven reply
+/// against its long-term key, measuring the offset against `clock`. Every
+/// address the server resolves to is tried until one answers.
+pub fn query_roughtime_server(
+    server: &RoughtimeServer,
+    timeout: Duration,
+    clock: &dyn SystemClock,
+) -> Result<RoughtimeSample, SourceError> {
+    let addrs = server
+        .address
+        .to_socket_addrs()
+        .map_err(|e| format!("Error resolving {}: {}", server.address, e))?;
+
+    let mut last_error = SourceError::from(format!("No addresses found for {}", server.address));
+    for addr in addrs {
+        let exchange = || -> Result<RoughtimeSample, SourceError> {
+            let socket = net::udp_socket(addr, timeout)?;
+
+            let mut nonce = [0u8; NONCE_LEN];
+            getrandom::getrandom(&mut nonce).map_err(|e| e.to_string())?;
+            let request = build_request(&nonce);
+
+            let sent = clock.now();
+            socket.send(&request).map_err(net::io_error)?;
+            let mut buffer = [0u8; 4096];
+            let bytes_read = socket.recv(&mut buffer).map_err(net::io_error)?;
+            let received = clock.now();
+
+            let (midpoint, radius) =
+                verify_response(&buffer[..bytes_read], &nonce, &server.public_key)?;
+            let local_midpoint = sent + (received - sent) / 2;
+            Ok(RoughtimeSample {
+                midpoint,
+                radius,
+                offset: midpoint - local_midpoint,
+                delay: received - sent,
+            })
+        };
+        match exchange() {
+            Ok(sample) => return Ok(sample),
+            Err(e) => {
+                println!("Skipping Roughtime server {}: {}", addr, e);
+                last_error = e;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+fn build_request(nonce: &[u8; NONCE_LEN]) -> Vec<u8> {
+    // Two tags: the count, one offset, both tags and then the values.
+    let mut request = Vec::with_capacity(REQUEST_LEN);
+    request.extend_from_slice(&2u32.to_le_bytes());
+    request.extend_from_slice(&(NONCE_LEN as u32).to_le_bytes());
+    request.extend_from_slice(&TAG_NONC.to_le_bytes());
+    request.extend_from_slice(&TAG_PAD.to_le_bytes());
+    request.extend_from_slice(nonce);
+    request.resize(REQUEST_LEN, 0);
+    request
+}
+
+fn verify_response(
+    response: &[u8],
+    nonce: &[u8; NONCE_LEN],
+    public_key: &VerifyingKey,
+) -> Result<(DateTime<Utc>, chrono::Duration), String> {
+    let message = Message::parse(response)?;
+    let signed_response = message.get(TAG_SREP)?;
+    let signature = message.get(TAG_SIG)?;
+    let path = message.get(TAG_PATH)?;
+    let index = message.get_u32(TAG_INDX)?;
+
+    let certificate = Message::parse(message.get(TAG_CERT)?)?;
+    let delegation_bytes = certificate.get(TAG_DELE)?;
+    verify_signature(
+        public_key,
+        DELEGATION_CONTEXT,
+        delegation_bytes,
+        certificate.get(TAG_SIG)?,
+    )
+    .map_err(|_| "Roughtime delegation signature is invalid")?;
+
+    let delegation = Message::parse(delegation_bytes)?;
+    let delegated_key: [u8; 32] = delegation
+        .get(TAG_PUBK)?
+        .try_into()
+        .map_err(|_| "Invalid Roughtime delegated key")?;
+    let delegated_key =
+        VerifyingKey::from_bytes(&delegated_key).map_err(|_| "Invalid Roughtime delegated key")?;
+    verify_signature(&delegated_key, RESPONSE_CONTEXT, signed_response, signature)
+        .map_err(|_| "Roughtime response signature is invalid")?;
+
+    let signed = Message::parse(signed_response)?;
+    if merkle_root(nonce, path, index)? != signed.get(TAG_ROOT)? {
+        return Err("Roughtime Merkle proof does not cover our nonce".into());
+    }
+
+    let midpoint = signed.get_u64(TAG_MIDP)?;
+    if midpoint < delegation.get_u64(TAG_MINT)? || midpoint > delegation.get_u64(TAG_MAXT)? {
+        return Err("Roughtime midpoint is outside the delegation's validity".into());
+    }
+    let midpoint =
+        DateTime::from_timestamp_micros(midpoint as i64).ok_or("Invalid Roughtime midpoint")?;
+    let radius = chrono::Duration::microseconds(signed.get_u32(TAG_RADI)? as i64);
+
+    Ok((midpoint, radius))
+}
+
+fn verify_signature(
+    key: &VerifyingKey,
+    context: &[u8],
+    data: &[u8],
+    signature: &[u8],
+) -> Result<(), String> {
+    let signature = Signature::from_slice(signature).map_err(|e| e.to_string())?;
+    key.verify(&[context, data].concat(), &signature)
+        .map_err(|e| e.to_string())
+}
+
+// Walks the Merkle path from our nonce's leaf up to the tree root.
+fn merkle_root(nonce: &[u8], path: &[u8], mut index: u32) -> Result<Vec<u8>, String> {
+    if !path.len().is_multiple_of(HASH_LEN) {
+        return Err("Invalid Roughtime Merkle path".into());
+    }
+    let mut hash = Sha512::new()
+        .chain_update([0x00])
+        .chain_update(nonce)
+        .finalize()
+        .to_vec();
+    for sibling in path.chunks(HASH_LEN) {
+        let (left, right) = match index & 1 {
+            0 => (hash.as_slice(), sibling),
+            _ => (sibling, hash.as_slice()),
+        };
+        hash = Sha512::new()
+            .chain_update([0x01])
+            .chain_update(left)
+            .chain_update(right)
+            .finalize()
+            .to_vec();
+        index >>= 1;
+    }
+    Ok(hash)
+}
+
+// A parsed Roughtime tag/value message.
+struct Message<'a> {
+    fields: Vec<(u32, &'a [u8])>,
+}
+
+impl<'a> Message<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, String> {
+        let invalid = || "Invalid Roughtime message".to_string();
+        let read_u32 = |offset: usize| -> Result<u32, String> {
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .ok_or_else(invalid)
+        };
+
+        let count = read_u32(0)? as usize;
+        if count == 0 || count > data.len() / 8 {
+            return Err(invalid());
+        }
+        let tags_start = 4 + 4 * (count - 1);
+        let values_start = tags_start + 4 * count;
+        let values = data.get(values_start..).ok_or_else(invalid)?;
+
+        let mut fields = Vec::with_capacity(count);
+        for i in 0..count {
+            let start = match i {
+                0 => 0,
+                _ => read_u32(4 + 4 * (i - 1))? as usize,
+            };
+            let end = match i + 1 < count {
+                true => read_u32(4 + 4 * i)? as usize,
+                false => values.len(),
+            };
+            if start > end || end > values.len() || start % 4 != 0 {
+                return Err(invalid());
+            }
+            fields.push((read_u32(tags_start + 4 * i)?, &values[start..end]));
+        }
+        Ok(Message { fields })
+    }
+
+    fn get(&self, tag: u32) -> Result<&'a [u8], String> {
+        self.fields
+            .iter()
+            .find(|(field_tag, _)| *field_tag == tag)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| {
+                format!(
+                    "Roughtime message is missing {}",
+                    String::from_utf8_lossy(&tag.to_le_bytes()).trim_end_matches('\0')
+                )
+            })
+    }
+
+    fn get_u32(&self, tag: u32) -> Result<u32, String> {
+        let value: [u8; 4] = self
+            .get(tag)?
+            .try_into()
+            .map_err(|_| "Invalid Roughtime integer")?;
+        Ok(u32::from_le_bytes(value))
+    }
+
+    fn get_u64(&self, tag: u32) -> Result<u64, String> {
+        let value: [u8; 8] = self
+            .get(tag)?
+            .try_into()
+            .map_err(|_| "Invalid Roughtime integer")?;
+        Ok(u64::from_le_bytes(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::OsClock;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::{net::UdpSocket, thread};
+
+    const MIDPOINT: u64 = 1_729_000_000_000_000;
+    const HOUR: u64 = 3_600_000_000;
+
+    // Lays out tag/value pairs the way a Roughtime server does.
+    fn encode(fields: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut message = (fields.len() as u32).to_le_bytes().to_vec();
+        let mut offset = 0;
+        for (_, value) in &fields[..fields.len() - 1] {
+            offset += value.len() as u32;
+            message.extend_from_slice(&offset.to_le_bytes());
+        }
+        for (tag, _) in fields {
+            message.extend_from_slice(&tag.to_le_bytes());
+        }
+        for (_, value) in fields {
+            message.extend_from_slice(value);
+        }
+        message
+    }
+
+    fn hash(prefix: u8, parts: &[&[u8]]) -> Vec<u8> {
+        let mut hasher = Sha512::new().chain_update([prefix]);
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().to_vec()
+    }
+
+    // A server with a long-term key that delegated to an online key for
+    // `mint..=maxt`.
+    struct StandInServer {
+        key: SigningKey,
+        online_key: SigningKey,
+        mint: u64,
+        maxt: u64,
+    }
+
+    impl StandInServer {
+        fn new() -> Self {
+            StandInServer {
+                key: SigningKey::from_bytes(&[1; 32]),
+                online_key: SigningKey::from_bytes(&[2; 32]),
+                mint: MIDPOINT - HOUR,
+                maxt: MIDPOINT + HOUR,
+            }
+        }
+
+        // Answers `nonce` batched with one other request, so it sits at
+        // index 1 of a two-leaf tree.
+        fn respond(&self, nonce: &[u8; NONCE_LEN], midpoint: u64) -> Vec<u8> {
+            let sibling = hash(0x00, &[&[7; NONCE_LEN]]);
+            let root = hash(0x01, &[&sibling, &hash(0x00, &[nonce])]);
+            let signed = encode(&[
+                (TAG_RADI, &1_000_000u32.to_le_bytes()),
+                (TAG_MIDP, &midpoint.to_le_bytes()),
+                (TAG_ROOT, &root),
+            ]);
+            let signature = self
+                .online_key
+                .sign(&[RESPONSE_CONTEXT, &signed].concat())
+                .to_bytes();
+            encode(&[
+                (TAG_SIG, &signature),
+                (TAG_PATH, &sibling),
+                (TAG_SREP, &signed),
+                (TAG_CERT, &self.certificate()),
+                (TAG_INDX, &1u32.to_le_bytes()),
+            ])
+        }
+
+        fn certificate(&self) -> Vec<u8> {
+            let delegation = encode(&[
+                (TAG_PUBK, self.online_key.verifying_key().as_bytes()),
+                (TAG_MINT, &self.mint.to_le_bytes()),
+                (TAG_MAXT, &self.maxt.to_le_bytes()),
+            ]);
+            let signature = self
+                .key
+                .sign(&[DELEGATION_CONTEXT, &delegation].concat())
+                .to_bytes();
+            encode(&[(TAG_SIG, &signature), (TAG_DELE, &delegation)])
+        }
+    }
+
+    fn verify(
+        server: &StandInServer,
+        response: &[u8],
+        nonce: &[u8; NONCE_LEN],
+    ) -> Result<(DateTime<Utc>, chrono::Duration), String> {
+        verify_response(response, nonce, &server.key.verifying_key())
+    }
+
+    #[test]
+    fn accepts_a_valid_response() {
+        let server = StandInServer::new();
+        let nonce = [3; NONCE_LEN];
+        let (midpoint, radius) =
+            verify(&server, &server.respond(&nonce, MIDPOINT), &nonce).unwrap();
+        assert_eq!(
+            midpoint,
+            DateTime::from_timestamp_micros(MIDPOINT as i64).unwrap()
+        );
+        assert_eq!(radius, chrono::Duration::seconds(1));
+    }
+
+    #[test]
+    fn rejects_a_delegation_from_another_key() {
+        let server = StandInServer::new();
+        let impostor = StandInServer {
+            key: SigningKey::from_bytes(&[9; 32]),
+            ..StandInServer::new()
+        };
+        let nonce = [3; NONCE_LEN];
+        let error = verify(&server, &impostor.respond(&nonce, MIDPOINT), &nonce).unwrap_err();
+        assert!(error.contains("delegation signature"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_a_tampered_response() {
+        let server = StandInServer::new();
+        let nonce = [3; NONCE_LEN];
+        let mut response = server.respond(&nonce, MIDPOINT);
+        let midpoint = response
+            .windows(8)
+            .position(|window| window == MIDPOINT.to_le_bytes())
+            .unwrap();
+        response[midpoint] ^= 1;
+        let error = verify(&server, &response, &nonce).unwrap_err();
+        assert!(error.contains("response signature"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_a_proof_for_another_nonce() {
+        let server = StandInServer::new();
+        let response = server.respond(&[3; NONCE_LEN], MIDPOINT);
+        let error = verify(&server, &response, &[4; NONCE_LEN]).unwrap_err();
+        assert!(error.contains("Merkle proof"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_a_wrong_merkle_path() {
+        let server = StandInServer::new();
+        let nonce = [3; NONCE_LEN];
+        let mut response = server.respond(&nonce, MIDPOINT);
+        // INDX is the last value: claim our nonce was the left leaf.
+        let index = response.len() - 4;
+        response[index..].copy_from_slice(&0u32.to_le_bytes());
+        let error = verify(&server, &response, &nonce).unwrap_err();
+        assert!(error.contains("Merkle proof"), "{}", error);
+    }
+
+    #[test]
+    fn walks_the_merkle_path() {
+        let nonce = [3; NONCE_LEN];
+        let leaf = hash(0x00, &[&nonce]);
+        let sibling = [5; HASH_LEN];
+        assert_eq!(merkle_root(&nonce, &[], 0).unwrap(), leaf);
+        assert_eq!(
+            merkle_root(&nonce, &sibling, 0).unwrap(),
+            hash(0x01, &[&leaf, &sibling])
+        );
+        assert_eq!(
+            merkle_root(&nonce, &sibling, 1).unwrap(),
+            hash(0x01, &[&sibling, &leaf])
+        );
+        assert!(merkle_root(&nonce, &sibling[..HASH_LEN - 1], 0).is_err());
+    }
+
+    #[test]
+    fn rejects_a_midpoint_before_the_delegation() {
+        let server = StandInServer {
+            mint: MIDPOINT + 1,
+            ..StandInServer::new()
+        };
+        let nonce = [3; NONCE_LEN];
+        let error = verify(&server, &server.respond(&nonce, MIDPOINT), &nonce).unwrap_err();
+        assert!(error.contains("outside the delegation"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_an_expired_delegation() {
+        let server = StandInServer {
+            mint: MIDPOINT - 2 * HOUR,
+            maxt: MIDPOINT - HOUR,
+            ..StandInServer::new()
+        };
+        let nonce = [3; NONCE_LEN];
+        let error = verify(&server, &server.respond(&nonce, MIDPOINT), &nonce).unwrap_err();
+        assert!(error.contains("outside the delegation"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_truncated_and_garbled_messages() {
+        let server = StandInServer::new();
+        let nonce = [3; NONCE_LEN];
+        let response = server.respond(&nonce, MIDPOINT);
+        for len in [0, 3, 8, 20, response.len() / 2, response.len() - 1] {
+            assert!(verify(&server, &response[..len], &nonce).is_err());
+        }
+
+        // More tags than the message has room for.
+        assert!(Message::parse(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]).is_err());
+        // An offset that isn't a multiple of four.
+        let mut misaligned = encode(&[(TAG_PAD, &[0; 4]), (TAG_NONC, &[0; 4])]);
+        misaligned[4] = 2;
+        assert!(Message::parse(&misaligned).is_err());
+        // Offsets that run backwards.
+        let mut backwards = encode(&[(TAG_PAD, &[0; 4]), (TAG_NONC, &[0; 4]), (TAG_ROOT, &[0; 4])]);
+        backwards[4] = 8;
+        backwards[8] = 4;
+        assert!(Message::parse(&backwards).is_err());
+
+        let short = encode(&[(TAG_MIDP, &[0; 4])]);
+        let message = Message::parse(&short).unwrap();
+        assert!(message.get_u64(TAG_MIDP).is_err());
+        assert!(message.get(TAG_RADI).unwrap_err().contains("RADI"));
+    }
+
+    fn stand_in_server(socket: UdpSocket) -> RoughtimeServer {
+        let address = socket.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buffer = [0u8; REQUEST_LEN];
+            let (length, client) = socket.recv_from(&mut buffer).unwrap();
+            let request = Message::parse(&buffer[..length]).unwrap();
+            let nonce = request.get(TAG_NONC).unwrap().try_into().unwrap();
+            let now = Utc::now().timestamp_micros() as u64;
+            let server = StandInServer {
+                mint: now - HOUR,
+                maxt: now + HOUR,
+                ..StandInServer::new()
+            };
+            socket.send_to(&server.respond(nonce, now), client).unwrap();
+        });
+        RoughtimeServer {
+            address,
+            public_key: StandInServer::new().key.verifying_key(),
+        }
+    }
+
+    #[test]
+    fn queries_a_local_server() {
+        let server = stand_in_server(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let sample = query_roughtime_server(&server, Duration::from_secs(2), &OsClock).unwrap();
+        assert!(sample.contains(chrono::Duration::zero()), "{:?}", sample);
+        assert_eq!(sample.radius, chrono::Duration::seconds(1));
+    }
+
+    #[test]
+    fn times_out_on_a_silent_server() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = RoughtimeServer {
+            address: socket.local_addr().unwrap().to_string(),
+            public_key: StandInServer::new().key.verifying_key(),
+        };
+        let error =
+            query_roughtime_server(&server, Duration::from_millis(100), &OsClock).unwrap_err();
+        assert!(matches!(error, SourceError::Timeout(_)), "{:?}", error);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::retry::Backoff;
+use std::time::{Duration, Instant};
+
+/// Parses an interval such as `90s`, `15m` or `2h`. A bare number is taken
+/// as minutes, which is what `--interval` used to accept.
+pub fn parse_interval(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit_secs) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1),
+        Some((i, 'm')) => (&value[..i], 60),
+        Some((i, 'h')) => (&value[..i], 60 * 60),
+        _ => (value, 60),
+    };
+    let number: u64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid interval {:?}, expected e.g. 90s, 15m or 2h", value))?;
+    match number.checked_mul(unit_secs) {
+        Some(0) => Err("Interval must be higher than 0".into()),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Err(format!("Interval {:?} is too long", value)),
+    }
+}
+
+/// Writes an interval out in the largest unit that divides it evenly.
+pub fn format_interval(interval: Duration) -> String {
+    let secs = interval.as_secs();
+    let (count, unit) = match secs {
+        0 => (0, "second"),
+        _ if secs.is_multiple_of(60 * 60) => (secs / (60 * 60), "hour"),
+        _ if secs.is_multiple_of(60) => (secs / 60, "minute"),
+        _ => (secs, "second"),
+    };
+    match count {
+        1 => format!("1 {}", unit),
+        _ => format!("{} {}s", count, unit),
+    }
+}
+
+/// Decides when the next sync runs: a regular interval after one that
+/// succeeded, an increasing backoff after each one that failed.
+///
+/// Times are taken from the monotonic clock, so correcting the system clock
+/// doesn't move the schedule.
+pub struct Scheduler {
+    next_run: Instant,
+    backoff: Backoff,
+}
+
+impl Scheduler {
+    /// The first sync is due right away. Retries never wait longer than
+    /// `max_interval`.
+    pub fn new(now: Instant, max_interval: Duration) -> Self {
+        Scheduler {
+            next_run: now,
+            backoff: Backoff::new(max_interval),
+        }
+    }
+
+    pub fn time_until_next(&self, now: Instant) -> Duration {
+        self.next_run.saturating_duration_since(now)
+    }
+
+    pub fn succeeded(&mut self, now: Instant, interval: Duration) {
+        self.backoff.reset();
+        self.next_run = now + interval;
+    }
+
+    /// Schedules a retry and returns how long it is away.
+    pub fn failed(&mut self, now: Instant) -> Duration {
+        let delay = self.backoff.next_delay();
+        self.next_run = now + delay;
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_interval("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_interval("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_interval("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_interval(" 30 s "), Ok(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn bare_number_is_minutes() {
+        assert_eq!(parse_interval("60"), Ok(Duration::from_secs(60 * 60)));
+        assert_eq!(parse_interval("1"), Ok(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn rejects_invalid_intervals() {
+        assert!(parse_interval("").is_err());
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("-5m").is_err());
+        assert!(parse_interval("1.5h").is_err());
+        assert!(parse_interval("10d").is_err());
+        assert!(parse_interval("h").is_err());
+        assert!(parse_interval(&format!("{}h", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn formats_in_largest_even_unit() {
+        assert_eq!(format_interval(Duration::from_secs(90)), "90 seconds");
+        assert_eq!(format_interval(Duration::from_secs(60)), "1 minute");
+        assert_eq!(format_interval(Duration::from_secs(15 * 60)), "15 minutes");
+        assert_eq!(format_interval(Duration::from_secs(2 * 60 * 60)), "2 hours");
+        assert_eq!(format_interval(Duration::from_millis(1500)), "1 second");
+    }
+
+    #[test]
+    fn first_sync_is_due_immediately() {
+        let now = Instant::now();
+        let scheduler = Scheduler::new(now, Duration::from_secs(60 * 60));
+        assert_eq!(scheduler.time_until_next(now), Duration::ZERO);
+    }
+
+    #[test]
+    fn success_waits_the_full_interval() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new(now, Duration::from_secs(60 * 60));
+        scheduler.succeeded(now, Duration::from_secs(90));
+        assert_eq!(scheduler.time_until_next(now), Duration::from_secs(90));
+        assert_eq!(
+            scheduler.time_until_next(now + Duration::from_secs(30)),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            scheduler.time_until_next(now + Duration::from_secs(120)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn failures_back_off_up_to_the_interval() {
+        let now = Instant::now();
+        let max_interval = Duration::from_secs(5 * 60);
+        let mut scheduler = Scheduler::new(now, max_interval);
+        let mut ceiling = Duration::from_secs(30);
+        for _ in 0..10 {
+            let delay = scheduler.failed(now);
+            assert_eq!(scheduler.time_until_next(now), delay);
+            assert!(delay >= ceiling / 2 && delay <= ceiling);
+            ceiling = (ceiling * 2).min(max_interval);
+        }
+    }
+
+    #[test]
+    fn success_resets_the_backoff() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new(now, Duration::from_secs(60 * 60));
+        for _ in 0..5 {
+            scheduler.failed(now);
+        }
+        scheduler.succeeded(now, Duration::from_secs(60));
+        assert!(scheduler.failed(now) <= Duration::from_secs(30));
+    }
+}
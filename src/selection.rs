@@ -0,0 +1,187 @@
+use crate::source::Sample;
+
+/// The offset agreed on by the truechimers among a set of samples.
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub offset: chrono::Duration,
+    /// Half the width of the interval the truechimers agree on, how far the
+    /// offset may be from the truth.
+    pub uncertainty: chrono::Duration,
+    /// Sources whose interval doesn't overlap the one most samples agree on.
+    pub falsetickers: Vec<String>,
+}
+
+/// Marzullo's intersection algorithm as used by NTP (RFC 5905, section
+/// 11.2.1).
+///
+/// Every sample is a confidence interval of `offset ± uncertainty`. The
+/// smallest interval that the largest number of samples overlap is taken as
+/// the truth, allowing fewer than half of them to be wrong. Samples that miss
+/// it are falsetickers, the rest are combined weighted by how certain they
+/// are.
+pub fn select(samples: &[Sample]) -> Result<Selection, String> {
+    let count = samples.len() as i32;
+    if count == 0 {
+        return Err("No samples to select from".into());
+    }
+
+    // Each sample contributes its lower edge, midpoint and upper edge.
+    let mut edges: Vec<(chrono::Duration, i32)> = samples
+        .iter()
+        .flat_map(|sample| {
+            let offset = sample.offset();
+            [
+                (offset - sample.uncertainty, -1),
+                (offset, 0),
+                (offset + sample.uncertainty, 1),
+            ]
+        })
+        .collect();
+    edges.sort();
+
+    let mut bounds = None;
+    let mut allowed = 0;
+    while 2 * allowed < count {
+        let (low, low_midpoints) = scan(edges.iter().copied(), count - allowed, -1);
+        let (high, high_midpoints) = scan(edges.iter().rev().copied(), count - allowed, 1);
+        if let (Some(low), Some(high)) = (low, high) {
+            // Midpoints outside the interval are also falsetickers.
+            if low_midpoints + high_midpoints <= allowed && low <= high {
+                bounds = Some((low, high));
+                break;
+            }
+        }
+        allowed += 1;
+    }
+    let (low, high) = bounds.ok_or("No majority of sources agrees on the time")?;
+
+    let mut falsetickers = Vec::new();
+    let mut weighted = 0.0;
+    let mut weights = 0.0;
+    for sample in samples {
+        let offset = sample.offset();
+        match offset + sample.uncertainty < low || offset - sample.uncertainty > high {
+            true => falsetickers.push(sample.source.clone()),
+            false => {
+                // Weight by inverse uncertainty, with a floor so a sample
+                // claiming to be perfect doesn't drown out the rest.
+                let uncertainty = sample.uncertainty.num_microseconds().unwrap_or(i64::MAX);
+                let weight = 1.0 / uncertainty.max(1) as f64;
+                weighted += offset.num_nanoseconds().unwrap_or(0) as f64 * weight;
+                weights += weight;
+            }
+        }
+    }
+
+    Ok(Selection {
+        offset: chrono::Duration::nanoseconds((weighted / weights).round() as i64),
+        uncertainty: (high - low) / 2,
+        falsetickers,
+    })
+}
+
+// Walks the edges from one end until `needed` intervals overlap. Returns that
+// point and how many midpoints were passed on the way.
+fn scan(
+    edges: impl Iterator<Item = (chrono::Duration, i32)>,
+    needed: i32,
+    direction: i32,
+) -> (Option<chrono::Duration>, i32) {
+    let mut overlapping = 0;
+    let mut midpoints = 0;
+    for (edge, kind) in edges {
+        overlapping += kind * direction;
+        if overlapping >= needed {
+            return (Some(edge), midpoints);
+        }
+        if kind == 0 {
+            midpoints += 1;
+        }
+    }
+    (None, midpoints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{ms, test_sample};
+    use chrono::Utc;
+
+    // Samples named after their index, each `(offset, uncertainty)` in ms.
+    fn samples(readings: &[(i64, i64)]) -> Vec<Sample> {
+        let now = Utc::now();
+        readings
+            .iter()
+            .enumerate()
+            .map(|(i, &(offset, uncertainty))| {
+                test_sample(&i.to_string(), now, ms(offset), ms(uncertainty))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn outvotes_two_liars_that_agree() {
+        let samples = samples(&[(1000, 50), (5000, 50), (1010, 50), (5002, 50), (990, 50)]);
+        let selection = select(&samples).unwrap();
+        assert_eq!(selection.falsetickers, ["1", "3"]);
+        assert_eq!(selection.offset, ms(1000));
+    }
+
+    #[test]
+    fn outvotes_a_single_liar() {
+        let samples = samples(&[(1000, 50), (1010, 50), (-3000, 50)]);
+        let selection = select(&samples).unwrap();
+        assert_eq!(selection.falsetickers, ["2"]);
+        assert_eq!(selection.offset, ms(1005));
+        assert!(selection.uncertainty <= ms(50));
+    }
+
+    #[test]
+    fn needs_a_majority() {
+        let samples = samples(&[(1000, 10), (5000, 10)]);
+        assert!(select(&samples).is_err());
+    }
+
+    #[test]
+    fn weights_by_uncertainty() {
+        let samples = samples(&[(1000, 40), (1010, 10)]);
+        let selection = select(&samples).unwrap();
+        assert!(selection.falsetickers.is_empty());
+        // Weights of 1/40 and 1/10.
+        assert_eq!(selection.offset, ms(1008));
+    }
+
+    #[test]
+    fn floors_the_weight_of_a_perfect_sample() {
+        let now = Utc::now();
+        let precise = |name| {
+            test_sample(
+                name,
+                now,
+                ms(1000) + chrono::Duration::microseconds(1),
+                chrono::Duration::microseconds(2),
+            )
+        };
+        let samples = [
+            test_sample("perfect", now, ms(1000), chrono::Duration::zero()),
+            precise("a"),
+            precise("b"),
+        ];
+        let selection = select(&samples).unwrap();
+        assert!(selection.falsetickers.is_empty());
+        // Zero uncertainty weighs as much as one microsecond, the same as the
+        // other two together.
+        assert_eq!(
+            selection.offset,
+            ms(1000) + chrono::Duration::nanoseconds(500)
+        );
+    }
+
+    #[test]
+    fn selects_a_single_sample() {
+        let selection = select(&samples(&[(250, 1000)])).unwrap();
+        assert_eq!(selection.offset, ms(250));
+        assert_eq!(selection.uncertainty, ms(1000));
+        assert!(select(&[]).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::clock::SystemClock;
+use chrono::{DateTime, Utc};
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::{fmt, time::Instant};
+
+/// One reading of a remote clock.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// Which source and server produced the sample.
+    pub source: String,
+    /// The server's time at the moment the reply was received.
+    pub server_time: DateTime<Utc>,
+    /// Our own clock at the moment the reply was received.
+    pub local_time: DateTime<Utc>,
+    /// When the request the server answered left.
+    pub sent: Instant,
+    /// When the answer arrived.
+    pub received: Instant,
+    /// How far `server_time` may be from the true time.
+    pub uncertainty: chrono::Duration,
+}
+
+impl Sample {
+    /// How far the server clock is ahead of ours.
+    pub fn offset(&self) -> chrono::Duration {
+        self.server_time - self.local_time
+    }
+
+    /// Network round trip of the exchange, the larger it is the less the
+    /// sample can be trusted.
+    pub fn delay(&self) -> chrono::Duration {
+        chrono::Duration::from_std(self.received - self.sent).unwrap_or(chrono::Duration::zero())
+    }
+}
+
+/// Why a source couldn't produce a sample.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceError {
+    /// The server didn't connect or answer within the configured timeout.
+    Timeout(String),
+    Failed(String),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceError::Timeout(message) | SourceError::Failed(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl From<String> for SourceError {
+    fn from(message: String) -> Self {
+        SourceError::Failed(message)
+    }
+}
+
+impl From<&str> for SourceError {
+    fn from(message: &str) -> Self {
+        SourceError::Failed(message.to_string())
+    }
+}
+
+/// Anything that can tell us the time.
+pub trait TimeSource {
+    /// Measures how far the server is from `clock`, which the sample's
+    /// `local_time` is read from.
+    fn sample(&mut self, clock: &dyn SystemClock) -> Result<Sample, SourceError>;
+}
+
+/// A sample from `source` that is `offset` ahead of `local_time`, received
+/// right away.
+/// Shorthand for the millisecond durations tests are full of.
+#[cfg(test)]
+pub fn ms(milliseconds: i64) -> chrono::Duration {
+    chrono::Duration::milliseconds(milliseconds)
+}
+
+#[cfg(test)]
+pub fn test_sample(
+    source: &str,
+    local_time: DateTime<Utc>,
+    offset: chrono::Duration,
+    uncertainty: chrono::Duration,
+) -> Sample {
+    let now = Instant::now();
+    Sample {
+        source: source.to_string(),
+        server_time: local_time + offset,
+        local_time,
+        sent: now,
+        received: now,
+        uncertainty,
+    }
+}
+
+/// Plays back a fixed list of samples and errors, one per call, for tests.
+#[cfg(test)]
+pub struct MockSource {
+    replies: VecDeque<Result<Sample, SourceError>>,
+}
+
+#[cfg(test)]
+impl MockSource {
+    pub fn new(replies: Vec<Result<Sample, SourceError>>) -> Self {
+        MockSource {
+            replies: replies.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl TimeSource for MockSource {
+    fn sample(&mut self, _clock: &dyn SystemClock) -> Result<Sample, SourceError> {
+        self.replies
+            .pop_front()
+            .unwrap_or_else(|| Err("No more replies".into()))
+    }
+}
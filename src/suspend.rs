@@ -0,0 +1,166 @@
+use crate::dbus::SystemBus;
+use std::{
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+// How often the clocks are compared to spot a suspend.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// CLOCK_BOOTTIME pulling ahead of CLOCK_MONOTONIC by more than this between
+// two checks means the machine was asleep.
+const MIN_SUSPEND: Duration = Duration::from_secs(2);
+
+const LOGIND_MATCH: &str = "type='signal',sender='org.freedesktop.login1',\
+    interface='org.freedesktop.login1.Manager',member='PrepareForSleep'";
+
+/// Sends on `wake` whenever the machine resumes from suspend, when the clock
+/// is most likely to be off.
+///
+/// CLOCK_MONOTONIC stops while the machine sleeps and CLOCK_BOOTTIME doesn't,
+/// so a growing gap between them gives a resume away within a few seconds.
+/// logind's PrepareForSleep signal, when it is there, reports it right away.
+pub fn watch(wake: Sender<()>) {
+    let reporter = Arc::new(ResumeReporter {
+        wake,
+        cycles: Mutex::new(SleepCycles::default()),
+    });
+
+    let clocks = Arc::clone(&reporter);
+    thread::spawn(move || {
+        let mut asleep = time_asleep();
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+            let now_asleep = time_asleep();
+            if now_asleep.saturating_sub(asleep) > MIN_SUSPEND {
+                clocks.report();
+            }
+            asleep = now_asleep;
+        }
+    });
+
+    thread::spawn(move || {
+        // Without logind the clocks still catch every resume.
+        let _ = watch_logind(&reporter);
+    });
+}
+
+struct ResumeReporter {
+    wake: Sender<()>,
+    cycles: Mutex<SleepCycles>,
+}
+
+impl ResumeReporter {
+    fn going_to_sleep(&self) {
+        self.cycles.lock().unwrap().going_to_sleep();
+    }
+
+    fn report(&self) {
+        if self.cycles.lock().unwrap().resumed(time_asleep()) {
+            println!("Resumed from suspend, syncing now");
+            let _ = self.wake.send(());
+        }
+    }
+}
+
+// Both watchers see the same resume, this makes sure only the first one
+// counts, and that the next sleep is reported again however soon it comes.
+#[derive(Default)]
+struct SleepCycles {
+    // logind said the machine is going to sleep and no resume was reported
+    // since.
+    sleeping: bool,
+    // Time spent asleep since boot when the last resume was reported.
+    reported: Option<Duration>,
+}
+
+impl SleepCycles {
+    fn going_to_sleep(&mut self) {
+        self.sleeping = true;
+    }
+
+    // Whether a resume seen after `asleep` in total was spent suspended is a
+    // new one, rather than the one the other watcher already reported.
+    fn resumed(&mut self, asleep: Duration) -> bool {
+        let slept_since = self
+            .reported
+            .is_none_or(|reported| asleep.saturating_sub(reported) > MIN_SUSPEND);
+        if !self.sleeping && !slept_since {
+            return false;
+        }
+        self.sleeping = false;
+        self.reported = Some(asleep);
+        true
+    }
+}
+
+fn watch_logind(reporter: &ResumeReporter) -> std::io::Result<()> {
+    let mut bus = SystemBus::connect()?;
+    bus.add_match(LOGIND_MATCH)?;
+    loop {
+        let signal = bus.next_signal()?;
+        if signal.interface != "org.freedesktop.login1.Manager"
+            || signal.member != "PrepareForSleep"
+        {
+            continue;
+        }
+        // The argument is true on the way down and false on the way up.
+        match signal.bool_arg() {
+            Some(true) => reporter.going_to_sleep(),
+            Some(false) => reporter.report(),
+            None => {}
+        }
+    }
+}
+
+// Total time spent suspended since boot.
+fn time_asleep() -> Duration {
+    read_clock(libc::CLOCK_BOOTTIME).saturating_sub(read_clock(libc::CLOCK_MONOTONIC))
+}
+
+fn read_clock(clock: libc::clockid_t) -> Duration {
+    let mut time: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe { libc::clock_gettime(clock, &mut time) };
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn reports_a_resume_seen_by_both_watchers_once() {
+        let mut cycles = SleepCycles::default();
+        cycles.going_to_sleep();
+        assert!(cycles.resumed(seconds(600)));
+        assert!(!cycles.resumed(seconds(600)));
+
+        // The clocks can also be the first to notice.
+        cycles.going_to_sleep();
+        assert!(cycles.resumed(seconds(1200)));
+        assert!(!cycles.resumed(seconds(1200)));
+    }
+
+    #[test]
+    fn reports_a_second_sleep_right_after_the_first() {
+        let mut cycles = SleepCycles::default();
+        cycles.going_to_sleep();
+        assert!(cycles.resumed(seconds(600)));
+        // Asleep again for under a second, well within a minute of the first
+        // resume.
+        cycles.going_to_sleep();
+        assert!(cycles.resumed(seconds(600) + Duration::from_millis(800)));
+    }
+
+    #[test]
+    fn reports_every_resume_the_clocks_see_without_logind() {
+        let mut cycles = SleepCycles::default();
+        assert!(cycles.resumed(seconds(600)));
+        assert!(cycles.resumed(seconds(630)));
+        assert!(!cycles.resumed(seconds(631)));
+    }
+}